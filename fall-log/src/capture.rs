@@ -0,0 +1,313 @@
+use crate::redact::Redact;
+use crate::ExtendedLog;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::Mutex;
+use tracing::field::Field;
+use tracing::field::Visit;
+use tracing::span::Attributes;
+use tracing::span::Record;
+use tracing::subscriber::DefaultGuard;
+use tracing::Event;
+use tracing::Id;
+use tracing::Level;
+use tracing::Subscriber;
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::registry::Registry;
+use tracing_subscriber::Layer;
+
+/// Log record.
+///
+/// A captured event with the fields of all its enclosing spans.
+#[derive(Clone, Debug)]
+pub struct LogRecord {
+    pub level: Level,
+    pub module: String,
+    pub message: String,
+    pub fields: BTreeMap<String, String>,
+    /// Span names, from root to the current span.
+    pub spans: Vec<String>,
+    /// Span fields, inner spans override outer ones.
+    pub span_fields: BTreeMap<String, String>,
+}
+
+impl LogRecord {
+    /// Event field, or span field if the event does not have it.
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields
+            .get(name)
+            .or_else(|| self.span_fields.get(name))
+            .map(String::as_str)
+    }
+
+    pub fn trace_id(&self) -> Option<&str> {
+        self.span_fields.get(super::TRACE_ID).map(String::as_str)
+    }
+}
+
+/// Record query.
+///
+/// Every condition set must match.
+#[derive(Clone, Debug, Default)]
+pub struct RecordQuery {
+    level: Option<Level>,
+    module: Option<String>,
+    message: Option<String>,
+    trace_id: Option<String>,
+    span: Option<String>,
+    fields: Vec<(String, Option<String>)>,
+}
+
+impl RecordQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn level(self, level: Level) -> Self {
+        RecordQuery {
+            level: Some(level),
+            ..self
+        }
+    }
+
+    /// Module path starting with `module`.
+    pub fn module(self, module: &str) -> Self {
+        RecordQuery {
+            module: Some(module.to_owned()),
+            ..self
+        }
+    }
+
+    /// Message containing `message`.
+    pub fn message(self, message: &str) -> Self {
+        RecordQuery {
+            message: Some(message.to_owned()),
+            ..self
+        }
+    }
+
+    pub fn trace_id(self, trace_id: &str) -> Self {
+        RecordQuery {
+            trace_id: Some(trace_id.to_owned()),
+            ..self
+        }
+    }
+
+    /// Logged inside a span named `span`.
+    pub fn span(self, span: &str) -> Self {
+        RecordQuery {
+            span: Some(span.to_owned()),
+            ..self
+        }
+    }
+
+    /// Field present, with any value.
+    pub fn field(mut self, name: &str) -> Self {
+        self.fields.push((name.to_owned(), None));
+        self
+    }
+
+    pub fn field_eq(mut self, name: &str, value: &str) -> Self {
+        self.fields.push((name.to_owned(), Some(value.to_owned())));
+        self
+    }
+
+    pub fn matches(&self, record: &LogRecord) -> bool {
+        self.level.map(|l| l == record.level).unwrap_or(true)
+            && self
+                .module
+                .as_ref()
+                .map(|m| record.module.starts_with(m.as_str()))
+                .unwrap_or(true)
+            && self
+                .message
+                .as_ref()
+                .map(|m| record.message.contains(m.as_str()))
+                .unwrap_or(true)
+            && self
+                .trace_id
+                .as_ref()
+                .map(|t| record.trace_id() == Some(t.as_str()))
+                .unwrap_or(true)
+            && self
+                .span
+                .as_ref()
+                .map(|s| record.spans.contains(s))
+                .unwrap_or(true)
+            && self.fields.iter().all(|(k, v)| match (record.field(k), v) {
+                (Some(a), Some(b)) => a == b,
+                (a, None) => a.is_some(),
+                _ => false,
+            })
+    }
+}
+
+/// CaptureLog.
+///
+/// A layer keeping structured records in memory, used by tests.
+#[derive(Clone, Default)]
+pub struct CaptureLog {
+    records: Arc<Mutex<Vec<LogRecord>>>,
+    redact: Option<Redact>,
+}
+
+impl CaptureLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn redact(self, redact: Redact) -> Self {
+        CaptureLog {
+            redact: Some(redact),
+            ..self
+        }
+    }
+
+    pub fn records(&self) -> Vec<LogRecord> {
+        self.records.lock().expect("Capture lock failed").clone()
+    }
+
+    pub fn clear(&self) {
+        self.records.lock().expect("Capture lock failed").clear();
+    }
+
+    pub fn find(&self, query: &RecordQuery) -> Vec<LogRecord> {
+        self.records
+            .lock()
+            .expect("Capture lock failed")
+            .iter()
+            .filter(|r| query.matches(r))
+            .cloned()
+            .collect()
+    }
+
+    /// Panic with all captured records if nothing matches `query`.
+    pub fn assert_logged(&self, query: &RecordQuery) {
+        if self.find(query).is_empty() {
+            panic!(
+                "No record matches {:?}, captured {:#?}",
+                query,
+                self.records()
+            );
+        }
+    }
+
+    /// Capture for the current thread only, until the guard is dropped.
+    pub fn set_default(&self) -> DefaultGuard {
+        let _ = tracing_log::LogTracer::init();
+        tracing::subscriber::set_default(Registry::default().with(self.clone()))
+    }
+}
+
+#[derive(Default)]
+struct FieldMap(BTreeMap<String, String>);
+
+impl Visit for FieldMap {
+    fn record_str(&mut self, f: &Field, value: &str) {
+        self.0.insert(f.name().to_owned(), value.to_owned());
+    }
+
+    fn record_debug(&mut self, f: &Field, value: &dyn Debug) {
+        let mut v = String::new();
+        let _ = write!(v, "{:?}", value);
+        self.0.insert(f.name().to_owned(), v);
+    }
+}
+
+struct CapturedSpan(FieldMap);
+
+impl<S> Layer<S> for CaptureLog
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("Span not found, this is a bug");
+        let mut fields = FieldMap::default();
+        attrs.record(&mut fields);
+        span.extensions_mut().insert(CapturedSpan(fields));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("Span not found, this is a bug");
+        let mut extensions = span.extensions_mut();
+        if let Some(s) = extensions.get_mut::<CapturedSpan>() {
+            values.record(&mut s.0);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let normalized = event.normalized_metadata();
+        let meta = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        let mut fields = FieldMap::default();
+        event.record(&mut fields);
+        let mut fields = fields.0;
+        fields.retain(|k, _| !k.starts_with("log."));
+        let mut message = fields.remove("message").unwrap_or_default();
+        let mut spans = vec![];
+        let mut span_fields = BTreeMap::new();
+        if let Some(span) = ctx.lookup_current() {
+            // Fields set by `set_span_field` and baggage, declared fields take precedence.
+            if let Some(ext) = span.extensions().get::<ExtendedLog>() {
+                span_fields.extend(ext.data.iter().map(|(k, v)| (k.clone(), v.clone())));
+            }
+            for s in span.scope().from_root() {
+                spans.push(s.name().to_owned());
+                if let Some(c) = s.extensions().get::<CapturedSpan>() {
+                    for (k, v) in (c.0).0.iter() {
+                        span_fields.insert(k.clone(), v.clone());
+                    }
+                }
+            }
+        }
+        if let Some(redact) = &self.redact {
+            redact.message(&mut message, 0);
+            for (k, v) in fields.iter_mut().chain(span_fields.iter_mut()) {
+                redact.value(k, v);
+            }
+        }
+        self.records
+            .lock()
+            .expect("Capture lock failed")
+            .push(LogRecord {
+                level: *meta.level(),
+                module: meta.module_path().unwrap_or("").to_owned(),
+                message,
+                fields,
+                spans,
+                span_fields,
+            });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::capture::*;
+    use crate::OpenTrace;
+
+    #[test]
+    fn test_capture() {
+        let capture = CaptureLog::new();
+        let _guard = capture.set_default();
+        let span: tracing::Span = OpenTrace::new(1, 2, None).into();
+        span.in_scope(|| {
+            tracing::warn!(x = 3, "hello {}", "fall");
+            log::info!("plain");
+        });
+        capture.assert_logged(
+            &RecordQuery::new()
+                .level(Level::WARN)
+                .field_eq("x", "3")
+                .trace_id("0000000000000001")
+                .message("hello fall"),
+        );
+        let info = capture.find(&RecordQuery::new().level(Level::INFO));
+        assert_eq!(1, info.len());
+        assert_eq!("fall_log::capture::test", info[0].module);
+        assert_eq!(vec!["new_span".to_owned()], info[0].spans);
+        assert!(capture.find(&RecordQuery::new().field("y")).is_empty());
+    }
+}
@@ -0,0 +1,115 @@
+use std::backtrace::Backtrace;
+use std::backtrace::BacktraceStatus;
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Once;
+
+static PANIC_HOOK: Once = Once::new();
+static LOG_PANICS: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static IN_HOOK: Cell<bool> = const { Cell::new(false) };
+    /// Location and backtrace of the last panic on this thread.
+    static LAST_PANIC: RefCell<Option<(String, String)>> = const { RefCell::new(None) };
+}
+
+/// Keep the location and backtrace of panics for `take_panic`, then run the previous hook.
+///
+/// The backtrace is captured as configured by `RUST_BACKTRACE`.
+pub fn capture_panics() {
+    PANIC_HOOK.call_once(|| {
+        let prev = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            // A panic while logging only runs the previous hook.
+            if !IN_HOOK.with(|h| h.replace(true)) {
+                on_panic(info);
+                IN_HOOK.with(|h| h.set(false));
+            }
+            prev(info);
+        }));
+    });
+}
+
+/// Log panics as `ERROR` events, then run the previous hook, which prints them to stderr by default.
+///
+/// The event is emitted on the panicking thread, so it carries the fields of the current span.
+pub fn install_panic_hook() {
+    LOG_PANICS.store(true, Ordering::SeqCst);
+    capture_panics();
+}
+
+/// Location and backtrace of the last panic on this thread, see `capture_panics`.
+pub fn take_panic() -> Option<(String, String)> {
+    LAST_PANIC.with(|p| p.borrow_mut().take())
+}
+
+fn on_panic(info: &std::panic::PanicHookInfo<'_>) {
+    let location = info.location().map(|l| l.to_string()).unwrap_or_default();
+    let backtrace = Backtrace::capture();
+    let backtrace = match backtrace.status() {
+        BacktraceStatus::Captured => backtrace.to_string(),
+        _ => String::new(),
+    };
+    if LOG_PANICS.load(Ordering::SeqCst) {
+        log_panic(info, &location, &backtrace);
+    }
+    LAST_PANIC.with(|p| *p.borrow_mut() = Some((location, backtrace)));
+}
+
+fn log_panic(info: &std::panic::PanicHookInfo<'_>, location: &str, backtrace: &str) {
+    let thread = std::thread::current();
+    let thread = thread.name().unwrap_or("<unnamed>");
+    let payload = info.payload();
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>");
+    tracing::error!(
+        target: "panic",
+        thread,
+        location = %location,
+        "thread '{}' panicked at {}: {}\n{}",
+        thread,
+        location,
+        message,
+        backtrace
+    );
+}
+
+/// Whether panics are already logged by `install_panic_hook`.
+pub fn panic_hook_installed() -> bool {
+    LOG_PANICS.load(Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hook::*;
+    use crate::*;
+
+    #[test]
+    fn test_panic_hook() {
+        let capture = CaptureLog::new();
+        let _guard = capture.set_default();
+        // Process-wide, other tests keep their panic output as the previous hook still runs.
+        install_panic_hook();
+        assert!(panic_hook_installed());
+        let span: tracing::Span = OpenTrace::new(1, 2, None).into();
+        let r = span.in_scope(|| std::panic::catch_unwind(|| panic!("boom")));
+        assert!(r.is_err());
+        let thread = std::thread::current();
+        capture.assert_logged(
+            &RecordQuery::new()
+                .level(Level::ERROR)
+                .trace_id("0000000000000001")
+                .field_eq("thread", thread.name().unwrap())
+                .field("location")
+                .message(": boom"),
+        );
+        let (location, _) = take_panic().unwrap();
+        assert!(location.contains("hook.rs"));
+        assert!(take_panic().is_none());
+    }
+}
@@ -1,6 +1,8 @@
+use crate::redact::Redact;
 use chrono::SecondsFormat;
 use chrono::Utc;
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::fmt::Display;
@@ -8,26 +10,49 @@ use std::fmt::Error;
 use std::fmt::Formatter;
 use std::fmt::Write;
 use std::io;
+use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
 use tracing::field::Field;
 use tracing::field::Visit;
 use tracing::span::Attributes;
-use tracing::span::Record;
 use tracing::subscriber::set_global_default;
 use tracing::subscriber::SetGlobalDefaultError;
 use tracing::Event;
 use tracing::Id;
-use tracing::Metadata;
 use tracing::Subscriber;
 use tracing_subscriber::layer::{Context, SubscriberExt};
 use tracing_subscriber::registry::LookupSpan;
 use tracing_subscriber::registry::Registry;
 use tracing_subscriber::Layer;
 
+mod capture;
+mod hook;
+mod redact;
+mod task;
+
+pub use capture::CaptureLog;
+pub use capture::LogRecord;
+pub use capture::RecordQuery;
+pub use fall_macros::instrument;
+pub use hook::capture_panics;
+pub use hook::install_panic_hook;
+pub use hook::panic_hook_installed;
+pub use hook::take_panic;
 pub use log::*;
+pub use regex::Regex;
+pub use task::block_traced;
+pub use task::child_span;
+pub use task::in_span;
+pub use task::instrument;
+pub use task::spawn_traced;
+pub use task::BlockingError;
 pub use tracing::field::display;
 pub use tracing::field::Empty;
 pub use tracing::span;
+pub use tracing::subscriber::DefaultGuard;
+pub use tracing::Instrument;
 pub use tracing::Level;
 pub use tracing_subscriber::registry::SpanRef;
 
@@ -35,14 +60,20 @@ const TRACE_ID: &str = "trace_id";
 const SPAN_ID: &str = "span_id";
 const PARENT_SPAN_ID: &str = "parent_span_id";
 pub const PADDING: &str = "padding";
+pub const REQUEST_ID: &str = "request_id";
+/// Prefix of baggage entries in span fields.
+pub const BAGGAGE_PREFIX: &str = "baggage.";
+/// Marker replacing redacted values.
+pub const REDACTED: &str = "***";
 
 /// Open tracing struct.
 ///
-///
+/// `baggage` holds business context propagated across services, like tenant or user id.
 pub struct OpenTrace {
     pub trace_id: String,
     pub span_id: String,
     pub parent_span_id: String,
+    pub baggage: BTreeMap<String, String>,
 }
 
 fn rand_u64() -> u64 {
@@ -56,24 +87,11 @@ fn u64_hex(i: u64) -> String {
 #[cfg(test)]
 extern crate quickcheck;
 #[cfg(test)]
+extern crate self as fall_log;
+#[cfg(test)]
 #[macro_use(quickcheck)]
 extern crate quickcheck_macros;
 
-#[cfg(test)]
-mod test {
-    use crate::*;
-
-    #[test]
-    fn test_rand() {
-        assert_ne!(rand_u64(), rand_u64());
-    }
-
-    #[quickcheck]
-    fn test_hex_len(i: u64) {
-        assert_eq!(16, u64_hex(i).len());
-    }
-}
-
 impl Default for OpenTrace {
     fn default() -> Self {
         let trace_id = u64_hex(rand_u64());
@@ -82,6 +100,7 @@ impl Default for OpenTrace {
             trace_id,
             span_id,
             parent_span_id: String::from(""),
+            baggage: BTreeMap::new(),
         }
     }
 }
@@ -92,27 +111,86 @@ impl OpenTrace {
             trace_id: u64_hex(trace_id),
             span_id: u64_hex(span_id),
             parent_span_id: parent_span_id.map(u64_hex).unwrap_or_else(|| "".into()),
+            baggage: BTreeMap::new(),
         }
     }
 
+    pub fn with_baggage(mut self, key: &str, value: &str) -> Self {
+        self.baggage.insert(key.to_owned(), value.to_owned());
+        self
+    }
+
     pub fn from_parent(trace_id: u64, parent_span_id: Option<u64>) -> Self {
         OpenTrace::new(trace_id, rand_u64(), parent_span_id)
     }
+
+    /// Span of an inbound request, named `request`.
+    pub fn request_span(self) -> span::Span {
+        let span = span!(
+            Level::INFO,
+            "request",
+            trace_id = %self.trace_id,
+            span_id = %self.span_id,
+            parent_span_id = %self.parent_span_id,
+            padding = Empty,
+            request_id = Empty,
+        );
+        add_baggage(&span, &self.baggage);
+        span
+    }
 }
 
 impl From<OpenTrace> for span::Span {
     fn from(ot: OpenTrace) -> Self {
-        span!(
+        let span = span!(
             Level::INFO,
             "new_span",
             trace_id = %ot.trace_id,
             span_id = %ot.span_id,
             parent_span_id = %ot.parent_span_id,
             padding = Empty,
-        )
+            request_id = Empty,
+        );
+        add_baggage(&span, &ot.baggage);
+        span
     }
 }
 
+/// Copy `baggage` into the fields of a new `span`.
+fn add_baggage(span: &span::Span, baggage: &BTreeMap<String, String>) {
+    if baggage.is_empty() {
+        return;
+    }
+    span.with_subscriber(|(id, r)| {
+        if let Some(span) = r.downcast_ref::<Registry>().and_then(|r| r.span(id)) {
+            if let Some(ext) = span.extensions_mut().get_mut::<ExtendedLog>() {
+                for (k, v) in baggage.iter() {
+                    ext.data
+                        .insert(format!("{}{}", BAGGAGE_PREFIX, k), v.clone());
+                }
+            }
+        }
+    });
+}
+
+/// Named span.
+///
+/// A child of the current trace named `name`, with a new span id.
+/// Fields of the parent span are inherited by `FallLog`.
+#[macro_export]
+macro_rules! named_span {
+    ($name:expr) => {{
+        let ot = $crate::new_child_span().unwrap_or_default();
+        $crate::span!(
+            $crate::Level::INFO,
+            $name,
+            trace_id = %ot.trace_id,
+            span_id = %ot.span_id,
+            parent_span_id = %ot.parent_span_id,
+        )
+    }};
+}
+
 struct EventWriter<'a>(&'a mut String);
 
 impl Visit for EventWriter<'_> {
@@ -128,10 +206,46 @@ pub fn current_trace_id() -> Option<String> {
     tracing::dispatcher::get_default(|r| {
         let span = r.downcast_ref::<Registry>()?.span(id)?;
         let ext = span.extensions();
-        ext.get::<ExtendedLog>()?
-            .data
-            .get(TRACE_ID)
-            .map(Clone::clone)
+        ext.get::<ExtendedLog>()?.data.get(TRACE_ID).cloned()
+    })
+}
+
+/// Set a field of the current span, visible to `FallLog` without declaring it.
+pub fn set_span_field(key: &str, value: &str) {
+    if let Some(id) = &span::Span::current().id() {
+        tracing::dispatcher::get_default(|r| {
+            if let Some(span) = r.downcast_ref::<Registry>().and_then(|r| r.span(id)) {
+                if let Some(ext) = span.extensions_mut().get_mut::<ExtendedLog>() {
+                    ext.data.insert(key.to_owned(), value.to_owned());
+                }
+            }
+        });
+    }
+}
+
+/// Field of the current span.
+pub fn current_span_field(key: &str) -> Option<String> {
+    let id = &span::Span::current().id()?;
+    tracing::dispatcher::get_default(|r| {
+        let span = r.downcast_ref::<Registry>()?.span(id)?;
+        let ext = span.extensions();
+        ext.get::<ExtendedLog>()?.data.get(key).cloned()
+    })
+}
+
+/// Trace of the current span.
+pub fn current_trace() -> Option<OpenTrace> {
+    let id = &span::Span::current().id()?;
+    tracing::dispatcher::get_default(|r| {
+        let span = r.downcast_ref::<Registry>()?.span(id)?;
+        let ext = span.extensions();
+        let map = &ext.get::<ExtendedLog>()?.data;
+        Some(OpenTrace {
+            trace_id: map.get(TRACE_ID)?.clone(),
+            span_id: map.get(SPAN_ID)?.clone(),
+            parent_span_id: map.get(PARENT_SPAN_ID).cloned().unwrap_or_default(),
+            baggage: baggage(map),
+        })
     })
 }
 
@@ -142,21 +256,46 @@ pub fn new_child_span() -> Option<OpenTrace> {
         let ext = span.extensions();
         let map = &ext.get::<ExtendedLog>()?.data;
         Some(OpenTrace {
-            trace_id: map.get(TRACE_ID).map(Clone::clone)?,
+            trace_id: map.get(TRACE_ID).cloned()?,
             span_id: u64_hex(rand_u64()),
-            parent_span_id: map.get(SPAN_ID).map(Clone::clone)?,
+            parent_span_id: map.get(SPAN_ID).cloned()?,
+            baggage: baggage(map),
         })
     })
 }
 
+fn baggage(map: &HashMap<String, String>) -> BTreeMap<String, String> {
+    map.iter()
+        .filter_map(|(k, v)| Some((k.strip_prefix(BAGGAGE_PREFIX)?.to_owned(), v.clone())))
+        .collect()
+}
+
+/// Set a baggage entry of the current span, inherited by child spans and forwarded to other services.
+pub fn set_baggage(key: &str, value: &str) {
+    set_span_field(&format!("{}{}", BAGGAGE_PREFIX, key), value);
+}
+
+/// Baggage entry of the current span.
+pub fn current_baggage(key: &str) -> Option<String> {
+    current_span_field(&format!("{}{}", BAGGAGE_PREFIX, key))
+}
+
+/// Adds secrets masked by a `FallLog`, see `FallLog::secrets_updater`.
+pub type SecretsUpdater = Box<dyn Fn(&[String]) + Send + Sync>;
+
 /// FallLog.
 ///
 /// A layer used to format normal log.
 pub struct FallLog<W: io::Write> {
-    writer: Mutex<W>,
+    writer: Arc<Mutex<W>>,
     max_level: Level,
     app_name: String,
     extend_fields: Vec<String>,
+    capture: Option<CaptureLog>,
+    panic_hook: bool,
+    span_close: bool,
+    baggage: bool,
+    redact: Redact,
 }
 
 impl<W> FallLog<W>
@@ -165,10 +304,30 @@ where
 {
     pub fn new(app_name: String, make_writer: W) -> Self {
         FallLog {
-            writer: Mutex::new(make_writer),
+            writer: Arc::new(Mutex::new(make_writer)),
             max_level: Level::INFO,
             app_name,
             extend_fields: vec![],
+            capture: None,
+            panic_hook: false,
+            span_close: false,
+            baggage: false,
+            redact: Redact::default(),
+        }
+    }
+
+    /// Same settings, writing to `writer` instead.
+    pub fn with_writer<V: io::Write + Send + 'static>(self, writer: V) -> FallLog<V> {
+        FallLog {
+            writer: Arc::new(Mutex::new(writer)),
+            max_level: self.max_level,
+            app_name: self.app_name,
+            extend_fields: self.extend_fields,
+            capture: self.capture,
+            panic_hook: self.panic_hook,
+            span_close: self.span_close,
+            baggage: self.baggage,
+            redact: self.redact,
         }
     }
 
@@ -188,10 +347,103 @@ where
         }
     }
 
-    pub fn init(self) -> Result<(), SetGlobalDefaultError> {
-        let subscriber = Registry::default().with(self);
+    /// Mask secret values appearing in log lines.
+    pub fn mask_secrets(self, secrets: Vec<String>) -> Self {
+        FallLog {
+            redact: self.redact.mask_secrets(secrets),
+            ..self
+        }
+    }
+
+    /// Add secrets to mask, still usable after `init`, like secrets resolved by a config reload.
+    pub fn secrets_updater(&self) -> SecretsUpdater {
+        let secrets = self.redact.secrets.clone();
+        Box::new(move |new: &[String]| {
+            let mut secrets = secrets.write().expect("Secrets lock failed");
+            for s in new {
+                if !s.is_empty() && !secrets.contains(s) {
+                    secrets.push(s.clone());
+                }
+            }
+        })
+    }
+
+    /// Mask values of fields named `names`, in span fields and as `name=value` in messages.
+    pub fn redact_fields(self, names: Vec<String>) -> Self {
+        FallLog {
+            redact: self.redact.redact_fields(names),
+            ..self
+        }
+    }
+
+    /// Mask matches of `patterns` in messages.
+    pub fn redact_patterns(self, patterns: Vec<Regex>) -> Self {
+        FallLog {
+            redact: self.redact.redact_patterns(patterns),
+            ..self
+        }
+    }
+
+    /// Flush the writer, still usable after `init`.
+    pub fn flusher(&self) -> Box<dyn Fn() + Send + Sync> {
+        let writer = self.writer.clone();
+        Box::new(move || {
+            if let Ok(mut w) = writer.lock() {
+                let _ = w.flush();
+            }
+        })
+    }
+
+    /// Also keep structured records in `capture`, redacted like log lines.
+    pub fn capture(self, capture: CaptureLog) -> Self {
+        FallLog {
+            capture: Some(capture),
+            ..self
+        }
+    }
+
+    /// Log panics through `init`, see `install_panic_hook`.
+    pub fn log_panics(self, enabled: bool) -> Self {
+        FallLog {
+            panic_hook: enabled,
+            ..self
+        }
+    }
+
+    /// Log span close with its busy and idle time.
+    pub fn log_span_close(self, enabled: bool) -> Self {
+        FallLog {
+            span_close: enabled,
+            ..self
+        }
+    }
+
+    /// Print baggage entries as `key=value` after the span fields.
+    pub fn log_baggage(self, enabled: bool) -> Self {
+        FallLog {
+            baggage: enabled,
+            ..self
+        }
+    }
+
+    pub fn init(mut self) -> Result<(), SetGlobalDefaultError> {
+        let capture = self.capture.take().map(|c| c.redact(self.redact.clone()));
+        let panic_hook = self.panic_hook;
+        let subscriber = Registry::default().with(self).with(capture);
+        let _ = tracing_log::LogTracer::init();
+        set_global_default(subscriber)?;
+        if panic_hook {
+            install_panic_hook();
+        }
+        Ok(())
+    }
+
+    /// Install for the current thread only, until the guard is dropped.
+    pub fn set_default(mut self) -> DefaultGuard {
+        let capture = self.capture.take().map(|c| c.redact(self.redact.clone()));
+        let subscriber = Registry::default().with(self).with(capture);
         let _ = tracing_log::LogTracer::init();
-        set_global_default(subscriber)
+        tracing::subscriber::set_default(subscriber)
     }
 }
 
@@ -200,6 +452,7 @@ where
 pub struct ExtendedLog {
     data: HashMap<String, String>,
     keys: Vec<String>,
+    baggage: bool,
 }
 
 impl Default for ExtendedLog {
@@ -212,21 +465,21 @@ impl Default for ExtendedLog {
                 PARENT_SPAN_ID.to_string(),
                 PADDING.to_string(),
             ],
+            baggage: false,
         }
     }
 }
 
 impl Visit for ExtendedLog {
     fn record_debug(&mut self, f: &Field, d: &dyn Debug) {
-        let name = f.name().to_owned();
-        if self.keys.contains(&name) {
-            self.data.insert(name, format!("{:?}", d));
-        }
+        self.data.insert(f.name().to_owned(), format!("{:?}", d));
     }
 }
 
-impl Display for ExtendedLog {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+impl ExtendedLog {
+    /// Write the displayed fields, values of `redact` fields masked.
+    fn write_fields<F: Write>(&self, f: &mut F, redact: &Redact) -> Result<(), Error> {
+        let masked = |k: &str| redact.is_field(k);
         let mut flag = false;
         for k in self.keys.iter() {
             if flag {
@@ -235,42 +488,43 @@ impl Display for ExtendedLog {
                 flag = true;
             }
             if let Some(v) = self.data.get(k) {
-                write!(f, "{}", v)?;
+                write!(f, "{}", if masked(k) { REDACTED } else { v })?;
+            }
+        }
+        if self.baggage {
+            for (k, v) in baggage(&self.data) {
+                let v = if masked(&k) { REDACTED } else { &v };
+                write!(f, ",{}={}", k, v)?;
             }
         }
         Ok(())
     }
 }
 
-impl<S: Subscriber, W> Layer<S> for FallLog<W>
-where
-    S: Subscriber + for<'a> LookupSpan<'a>,
-    W: io::Write + 'static,
-{
-    fn enabled(&self, metadata: &Metadata<'_>, _: Context<'_, S>) -> bool {
-        metadata.level() <= &self.max_level
+impl Display for ExtendedLog {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        self.write_fields(f, &Redact::default())
     }
+}
 
-    fn new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
-        let span = ctx.span(id).expect("Span not found, this is a bug");
-        let mut extensions = span.extensions_mut();
-        let mut info = ExtendedLog::default();
-        for k in self.extend_fields.iter() {
-            info.keys.push(k.to_owned());
-        }
-        attrs.record(&mut info);
-        extensions.insert(info);
-    }
-    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
-        let span = ctx.span(id).expect("Span not found, this is a bug");
-        let mut extensions = span.extensions_mut();
-        if let Some(sl) = extensions.get_mut::<ExtendedLog>() {
-            values.record(sl);
-        }
-    }
-    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+/// Busy and idle time of a span, kept when span close is logged.
+struct Timings {
+    busy: Duration,
+    idle: Duration,
+    last: Instant,
+}
+
+impl<W: io::Write> FallLog<W> {
+    /// Write a line with the fields of `info`, secrets masked.
+    fn write_line(
+        &self,
+        level: &Level,
+        info: Option<&ExtendedLog>,
+        module: &str,
+        message: impl FnOnce(&mut String),
+    ) {
         thread_local! {
-            static BUF: RefCell<String> = RefCell::new(String::new());
+            static BUF: RefCell<String> = const { RefCell::new(String::new()) };
         }
 
         BUF.with(|buf| {
@@ -291,26 +545,22 @@ where
                 &mut buf,
                 "{} {}",
                 Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
-                event.metadata().level()
+                level
             );
-            let mut flag = false;
-            if let Some(id) = ctx.current_span().id() {
-                let span = ctx.span(id).expect("Span not found, this is a bug");
-                let extensions = span.extensions();
-                if let Some(info) = extensions.get::<ExtendedLog>() {
-                    let _ = write!(&mut buf, " [{},{}]", self.app_name, info);
-                    flag = true;
+            match info {
+                Some(info) => {
+                    let _ = write!(&mut buf, " [{},", self.app_name);
+                    let _ = info.write_fields(buf, &self.redact);
+                    buf.push(']');
+                }
+                _ => {
+                    let _ = write!(&mut buf, " [{},]", self.app_name);
                 }
             }
-            if !flag {
-                let _ = write!(&mut buf, " [{},]", self.app_name);
-            }
-            let _ = write!(
-                &mut buf,
-                " {}: ",
-                event.metadata().module_path().unwrap_or("")
-            );
-            event.record(&mut EventWriter(&mut buf));
+            let _ = write!(&mut buf, " {}: ", module);
+            let start = buf.len();
+            message(buf);
+            self.redact.message(buf, start);
             let _ = writeln!(&mut buf);
             let _ = self
                 .writer
@@ -321,3 +571,216 @@ where
         });
     }
 }
+
+impl<S: Subscriber, W> Layer<S> for FallLog<W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: io::Write + 'static,
+{
+    fn enabled(&self, metadata: &tracing::Metadata<'_>, _: Context<'_, S>) -> bool {
+        metadata.level() <= &self.max_level
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("Span not found, this is a bug");
+        let mut info = ExtendedLog::default();
+        if let Some(parent) = span.parent() {
+            if let Some(p) = parent.extensions().get::<ExtendedLog>() {
+                info.data = p.data.clone();
+            }
+        }
+        let mut extensions = span.extensions_mut();
+        for k in self.extend_fields.iter() {
+            info.keys.push(k.to_owned());
+        }
+        info.baggage = self.baggage;
+        attrs.record(&mut info);
+        extensions.insert(info);
+        if self.span_close {
+            extensions.insert(Timings {
+                busy: Duration::default(),
+                idle: Duration::default(),
+                last: Instant::now(),
+            });
+        }
+    }
+    fn on_record(&self, id: &Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("Span not found, this is a bug");
+        let mut extensions = span.extensions_mut();
+        if let Some(sl) = extensions.get_mut::<ExtendedLog>() {
+            values.record(sl);
+        }
+    }
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("Span not found, this is a bug");
+        let mut extensions = span.extensions_mut();
+        if let Some(t) = extensions.get_mut::<Timings>() {
+            let now = Instant::now();
+            t.idle += now - t.last;
+            t.last = now;
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("Span not found, this is a bug");
+        let mut extensions = span.extensions_mut();
+        if let Some(t) = extensions.get_mut::<Timings>() {
+            let now = Instant::now();
+            t.busy += now - t.last;
+            t.last = now;
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = ctx.span(&id).expect("Span not found, this is a bug");
+        let extensions = span.extensions();
+        if let Some(t) = extensions.get::<Timings>() {
+            let idle = t.idle + t.last.elapsed();
+            let meta = span.metadata();
+            self.write_line(
+                meta.level(),
+                extensions.get::<ExtendedLog>(),
+                meta.module_path().unwrap_or(""),
+                |buf| {
+                    let _ = write!(
+                        buf,
+                        "close {}, busy={:?}, idle={:?}",
+                        meta.name(),
+                        t.busy,
+                        idle
+                    );
+                },
+            );
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let span = ctx
+            .current_span()
+            .id()
+            .map(|id| ctx.span(id).expect("Span not found, this is a bug"));
+        let extensions = span.as_ref().map(|s| s.extensions());
+        self.write_line(
+            event.metadata().level(),
+            extensions.as_ref().and_then(|e| e.get::<ExtendedLog>()),
+            event.metadata().module_path().unwrap_or(""),
+            |buf| event.record(&mut EventWriter(buf)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+
+    #[test]
+    fn test_rand() {
+        assert_ne!(rand_u64(), rand_u64());
+    }
+
+    #[quickcheck]
+    fn test_hex_len(i: u64) {
+        assert_eq!(16, u64_hex(i).len());
+    }
+
+    #[derive(Clone, Default)]
+    struct Buf(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_span_close() {
+        let buf = Buf::default();
+        let _guard = FallLog::new("test".to_owned(), buf.clone())
+            .log_span_close(true)
+            .set_default();
+        in_span(named_span!("phase"), || info!("inside"));
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let close: Vec<&str> = out.lines().filter(|l| l.contains("phase")).collect();
+        assert_eq!(1, close.len());
+        let close = close[0];
+        assert!(close.contains("close phase"));
+        assert!(close.starts_with(|c: char| c.is_ascii_digit()));
+        assert!(close.contains(" INFO [test,"));
+        assert!(close.contains("busy="));
+        assert!(close.contains("idle="));
+    }
+
+    #[test]
+    fn test_secrets_updater() {
+        let buf = Buf::default();
+        let log = FallLog::new("test".to_owned(), buf.clone()).mask_secrets(vec!["old".to_owned()]);
+        let update = log.secrets_updater();
+        let _guard = log.set_default();
+        update(&["new".to_owned()]);
+        info!("old new");
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        assert!(out.ends_with("*** ***\n"));
+    }
+
+    #[test]
+    fn test_redact() {
+        let buf = Buf::default();
+        let capture = CaptureLog::new();
+        let _guard = FallLog::new("test".to_owned(), buf.clone())
+            .capture(capture.clone())
+            .mask_secrets(vec!["s3cret".to_owned()])
+            .add_field("authorization".to_owned())
+            .redact_fields(vec!["password".to_owned(), "authorization".to_owned()])
+            .redact_patterns(vec![Regex::new(r"\d{4}(-\d{4}){3}").unwrap()])
+            .set_default();
+        let span: span::Span = OpenTrace::new(1, 2, None).into();
+        span.in_scope(|| {
+            set_span_field("authorization", "Bearer abc");
+            info!("pay 1234-5678-9012-3456, Password: p1 authorization=\"x y\" user=u1");
+            tracing::info!(key = "s3cret", "key s3cret");
+        });
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        assert!(out.contains(",***] "));
+        assert!(out.contains("pay ***, Password: *** authorization=*** user=u1\n"));
+        assert!(out.ends_with("key ***\n"));
+        // Captured records are redacted the same way.
+        capture.assert_logged(
+            &RecordQuery::new()
+                .field_eq("authorization", REDACTED)
+                .message("pay ***, Password: *** authorization=*** user=u1"),
+        );
+        capture.assert_logged(
+            &RecordQuery::new()
+                .field_eq("key", REDACTED)
+                .message("key ***"),
+        );
+    }
+
+    #[test]
+    fn test_baggage() {
+        let buf = Buf::default();
+        let _guard = FallLog::new("test".to_owned(), buf.clone())
+            .log_baggage(true)
+            .set_default();
+        let span: span::Span = OpenTrace::new(1, 2, None)
+            .with_baggage("tenant_id", "t1")
+            .into();
+        span.in_scope(|| {
+            set_baggage("user_id", "u1");
+            in_span(named_span!("child"), || {
+                assert_eq!(Some("t1".to_owned()), current_baggage("tenant_id"));
+                let child = new_child_span().unwrap();
+                assert_eq!("u1", child.baggage["user_id"]);
+                info!("inside");
+            });
+        });
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let line = out.lines().find(|l| l.ends_with("inside")).unwrap();
+        assert!(line.contains(",tenant_id=t1,user_id=u1]"));
+    }
+}
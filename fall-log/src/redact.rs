@@ -0,0 +1,88 @@
+use crate::REDACTED;
+use regex::NoExpand;
+use regex::Regex;
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+/// Redaction.
+///
+/// Masks field values, patterns and secrets, in log lines and in captured records.
+#[derive(Clone, Default)]
+pub(crate) struct Redact {
+    pub(crate) fields: Vec<String>,
+    field_pattern: Option<Regex>,
+    patterns: Vec<Regex>,
+    pub(crate) secrets: Arc<RwLock<Vec<String>>>,
+}
+
+impl Redact {
+    pub(crate) fn redact_fields(self, names: Vec<String>) -> Self {
+        let names: Vec<String> = names.into_iter().filter(|s| !s.is_empty()).collect();
+        let alt: Vec<String> = names.iter().map(|n| regex::escape(n)).collect();
+        let field_pattern = if alt.is_empty() {
+            None
+        } else {
+            let p = format!(
+                r#"(?i)\b({})(\s*[=:]\s*)("[^"]*"|[^\s,;&]+)"#,
+                alt.join("|")
+            );
+            Some(Regex::new(&p).expect("Escaped field names are a valid regex"))
+        };
+        Redact {
+            fields: names,
+            field_pattern,
+            ..self
+        }
+    }
+
+    pub(crate) fn redact_patterns(mut self, patterns: Vec<Regex>) -> Self {
+        self.patterns.extend(patterns);
+        self
+    }
+
+    pub(crate) fn mask_secrets(self, secrets: Vec<String>) -> Self {
+        let secrets = secrets.into_iter().filter(|s| !s.is_empty()).collect();
+        Redact {
+            secrets: Arc::new(RwLock::new(secrets)),
+            ..self
+        }
+    }
+
+    pub(crate) fn is_field(&self, name: &str) -> bool {
+        self.fields.iter().any(|r| r.eq_ignore_ascii_case(name))
+    }
+
+    /// Mask fields and patterns in `buf` after `start`, then secrets in all of it.
+    pub(crate) fn message(&self, buf: &mut String, start: usize) {
+        if let Some(p) = &self.field_pattern {
+            let rep = format!("${{1}}${{2}}{}", REDACTED);
+            if let Cow::Owned(m) = p.replace_all(&buf[start..], rep.as_str()) {
+                buf.replace_range(start.., &m);
+            }
+        }
+        for p in self.patterns.iter() {
+            if let Cow::Owned(m) = p.replace_all(&buf[start..], NoExpand(REDACTED)) {
+                buf.replace_range(start.., &m);
+            }
+        }
+        self.replace_secrets(buf);
+    }
+
+    /// Mask the value of field `name`.
+    pub(crate) fn value(&self, name: &str, value: &mut String) {
+        if self.is_field(name) {
+            *value = REDACTED.to_owned();
+        } else {
+            self.replace_secrets(value);
+        }
+    }
+
+    fn replace_secrets(&self, buf: &mut String) {
+        for s in self.secrets.read().expect("Secrets lock failed").iter() {
+            if buf.contains(s.as_str()) {
+                *buf = buf.replace(s.as_str(), REDACTED);
+            }
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use crate::new_child_span;
+use std::fmt::Debug;
+use std::future::Future;
+use tracing::dispatcher;
+use tracing::instrument::WithSubscriber;
+use tracing::Dispatch;
+use tracing::Instrument;
+use tracing::Span;
+
+pub use actix_threadpool::BlockingError;
+use actix_threadpool::CpuFuture;
+
+/// Span for work leaving the current task.
+///
+/// A child of the current trace, or a new trace if there is none.
+pub fn child_span() -> Span {
+    new_child_span().unwrap_or_default().into()
+}
+
+/// Spawn `fut` on the current arbiter, inside a child span.
+pub fn spawn_traced<F>(fut: F)
+where
+    F: Future<Output = ()> + 'static,
+{
+    actix_rt::spawn(fut.instrument(child_span()).with_current_subscriber());
+}
+
+/// Run `f` on the blocking thread pool like `web::block`, inside a child span.
+pub fn block_traced<F, I, E>(f: F) -> CpuFuture<I, E>
+where
+    F: FnOnce() -> Result<I, E> + Send + 'static,
+    I: Send + 'static,
+    E: Send + Debug + 'static,
+{
+    let span = child_span();
+    let dispatch = dispatcher::get_default(Dispatch::clone);
+    actix_threadpool::run(move || dispatcher::with_default(&dispatch, || span.in_scope(f)))
+}
+
+/// Run `fut` inside `span`, see `FallLog::log_span_close` to log its duration at close.
+pub async fn instrument<F: Future>(span: Span, fut: F) -> F::Output {
+    fut.instrument(span).await
+}
+
+/// Run `f` inside `span`, see `FallLog::log_span_close` to log its duration at close.
+pub fn in_span<T>(span: Span, f: impl FnOnce() -> T) -> T {
+    span.in_scope(f)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::task::*;
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn test_traced() {
+        let capture = CaptureLog::new();
+        let _guard = FallLog::new("test".to_owned(), std::io::sink())
+            .capture(capture.clone())
+            .set_default();
+        let span: Span = OpenTrace::new(1, 2, None).into();
+        let block = span.in_scope(|| {
+            spawn_traced(async { info!("spawned") });
+            block_traced(|| {
+                info!("blocking");
+                Ok::<_, ()>(())
+            })
+        });
+        block.await.unwrap();
+        actix_rt::time::delay_for(std::time::Duration::from_millis(10)).await;
+        for message in &["spawned", "blocking"] {
+            capture.assert_logged(
+                &RecordQuery::new()
+                    .message(message)
+                    .trace_id("0000000000000001")
+                    .field_eq(PARENT_SPAN_ID, "0000000000000002"),
+            );
+        }
+    }
+
+    #[instrument("load")]
+    async fn load(x: u32) -> u32 {
+        info!("loading");
+        compute() + x
+    }
+
+    #[instrument]
+    fn compute() -> u32 {
+        info!("computing");
+        1
+    }
+
+    #[actix_rt::test]
+    async fn test_instrument() {
+        let capture = CaptureLog::new();
+        let _guard = FallLog::new("test".to_owned(), std::io::sink())
+            .capture(capture.clone())
+            .set_default();
+        let span: Span = OpenTrace::new(1, 2, None).into();
+        assert_eq!(3, instrument(span, load(2)).await);
+        let q = RecordQuery::new().trace_id("0000000000000001");
+        capture.assert_logged(&q.clone().message("loading").span("load"));
+        capture.assert_logged(&q.clone().message("computing").span("compute"));
+        let loading = capture.find(&q.message("loading"));
+        assert_ne!("0000000000000002", loading[0].span_fields[SPAN_ID]);
+        assert_eq!("0000000000000002", loading[0].span_fields[PARENT_SPAN_ID]);
+    }
+}
@@ -0,0 +1,34 @@
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::parse_macro_input;
+use syn::ItemFn;
+use syn::LitStr;
+
+/// Instrument.
+///
+/// Run the function inside a named child span, see `fall_log::named_span!`.
+/// The span is named after the function unless a name is given, `#[instrument("name")]`.
+#[proc_macro_attribute]
+pub fn instrument(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = parse_macro_input!(item as ItemFn);
+    let name = if attr.is_empty() {
+        sig.ident.to_string()
+    } else {
+        parse_macro_input!(attr as LitStr).value()
+    };
+    let body = if sig.asyncness.is_some() {
+        quote! {
+            ::fall_log::instrument(::fall_log::named_span!(#name), async move #block).await
+        }
+    } else {
+        quote! {
+            ::fall_log::in_span(::fall_log::named_span!(#name), move || #block)
+        }
+    };
+    quote!(#(#attrs)* #vis #sig { #body }).into()
+}
@@ -1,3 +1,4 @@
+use crate::web::write_baggage;
 use actix_http::http::HeaderName;
 use actix_http::http::HeaderValue;
 use actix_http::http::Method;
@@ -30,10 +31,14 @@ impl ClientRequestExt for ClientRequest {
     }
     fn set_trace(self) -> Self {
         if let Some(s) = new_child_span() {
-            return self
+            let req = self
                 .header("X-B3-TraceId", s.trace_id)
                 .header("X-B3-SpanId", s.span_id)
                 .header("X-B3-ParentSpanId", s.parent_span_id);
+            if s.baggage.is_empty() {
+                return req;
+            }
+            return req.header("baggage", write_baggage(&s.baggage));
         }
         self
     }
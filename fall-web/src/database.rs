@@ -1,5 +1,7 @@
 use crate::endpoints::CheckHealth;
 use crate::error::FallError;
+use crate::section::ConfigSection;
+use crate::section::ValidationErrors;
 use crate::PoolConfig;
 use diesel::{
     connection::Connection,
@@ -33,6 +35,19 @@ impl CheckHealth for DatabaseConn {
     }
 }
 
+impl ConfigSection for DatabaseConfig {
+    const KEY: &'static str = "database";
+
+    fn validate(&self, errors: &mut ValidationErrors) {
+        if self.url.is_empty() {
+            errors.add("url", "must not be empty");
+        }
+        if let Some(pool) = &self.pool {
+            pool.validate(errors);
+        }
+    }
+}
+
 impl DatabaseConfig {
     pub fn init(&self) -> Result<DatabaseConn, FallError> {
         info!("Init database...");
@@ -1,4 +1,8 @@
+use crate::env::PropertyValue;
+use crate::env::Sanitizer;
 use crate::error::FallError;
+use crate::reload::SharedConfig;
+use crate::shutdown::Readiness;
 use crate::Application;
 use actix_web::web::resource;
 use actix_web::web::Data;
@@ -12,14 +16,9 @@ pub trait CheckHealth {
     fn check(&self) -> Result<(), FallError>;
 }
 
+#[derive(Default)]
 pub struct HealthList(BTreeMap<String, Box<dyn CheckHealth>>);
 
-impl Default for HealthList {
-    fn default() -> Self {
-        HealthList(BTreeMap::new())
-    }
-}
-
 impl HealthList {
     pub fn new() -> Self {
         Self::default()
@@ -30,8 +29,37 @@ impl HealthList {
     }
 }
 
-async fn info(app: Data<Application>) -> HttpResponse {
-    HttpResponse::Ok().json(app.as_ref())
+async fn info(app: Data<Application>, shared: Data<SharedConfig>) -> HttpResponse {
+    let sanitizer = Sanitizer::default().secrets(shared.get_sources().secrets());
+    match serde_json::to_string(app.as_ref()) {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(sanitizer.mask_secrets(&body)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("{}", e)),
+    }
+}
+
+#[derive(Serialize)]
+struct Env {
+    sources: Vec<String>,
+    properties: BTreeMap<String, PropertyValue>,
+}
+
+async fn env(shared: Data<SharedConfig>) -> HttpResponse {
+    let sources = shared.get_sources();
+    let sanitizer = Sanitizer::from_config(&shared.get()).secrets(sources.secrets());
+    let mut properties = sources.effective();
+    for (k, v) in properties.iter_mut() {
+        v.value = sanitizer.sanitize(k, &v.value);
+    }
+    HttpResponse::Ok().json(&Env {
+        sources: sources
+            .sources()
+            .iter()
+            .map(|s| s.name().to_owned())
+            .collect(),
+        properties,
+    })
 }
 
 fn modify_health(re: Result<(), FallError>, name: String, health: &mut Health) {
@@ -70,6 +98,23 @@ async fn endpoint_health(app: Data<HealthList>) -> HttpResponse {
     HttpResponse::Ok().json(&health)
 }
 
+async fn readiness(ready: Data<Readiness>) -> HttpResponse {
+    let (status, err) = match ready.check() {
+        Ok(()) => (HealthStatus::UP, None),
+        Err(e) => (HealthStatus::DOWN, Some(format!("{}", e))),
+    };
+    let mut res = match status {
+        HealthStatus::UP => HttpResponse::Ok(),
+        HealthStatus::DOWN => HttpResponse::ServiceUnavailable(),
+    };
+    res.json(&Health {
+        status,
+        err,
+        detail: BTreeMap::new(),
+    })
+}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Deserialize, Serialize)]
 enum HealthStatus {
     UP,
@@ -87,5 +132,7 @@ struct Health {
 
 pub fn endpoints(cfg: &mut ServiceConfig) {
     cfg.service(resource("/endpoints/info").to(info))
-        .service(resource("/endpoints/health").to(endpoint_health));
+        .service(resource("/endpoints/health").to(endpoint_health))
+        .service(resource("/endpoints/health/readiness").to(readiness))
+        .service(resource("/endpoints/env").to(env));
 }
@@ -1,15 +1,71 @@
+use crate::i18n;
+use crate::i18n::Catalog;
+use crate::section::ValidationErrors;
 use actix_http::body::Body;
 use actix_http::client::SendRequestError;
 use actix_http::http::header;
 use actix_http::Response;
 use actix_web::error::JsonPayloadError;
+use actix_web::error::PathError;
+use actix_web::error::QueryPayloadError;
 use actix_web::http::header::ToStrError;
 use actix_web::http::StatusCode;
+use actix_web::HttpRequest;
 use actix_web::ResponseError;
+use config::Config;
 use fall_log::*;
 use serde::Serialize;
+use serde_json::Map;
+use serde_json::Value;
 use std::fmt::{Display, Formatter};
 use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+
+/// Members of error bodies, not allowed as extensions.
+const RESERVED_MEMBERS: [&str; 9] = [
+    "type", "title", "status", "detail", "instance", "trace_id", "message", "code", "errors",
+];
+
+/// Error response settings.
+///
+/// `application.error.*` and the message catalog of an app, registered as app data
+/// and passed to error bodies by `FallTransform`.
+#[derive(Clone, Default)]
+pub struct ErrorConfig {
+    problem_json: bool,
+    show_details: bool,
+    catalog: Arc<Catalog>,
+}
+
+impl ErrorConfig {
+    pub(crate) fn load(config: &Config) -> Result<Self, config::ConfigError> {
+        Ok(ErrorConfig {
+            problem_json: config
+                .get::<bool>("application.error.problem_json")
+                .unwrap_or(false),
+            show_details: config
+                .get::<bool>("application.error.show_details")
+                .unwrap_or(false),
+            catalog: Arc::new(i18n::load(config)?),
+        })
+    }
+}
+
+/// Message followed by every cause, skipping causes repeating the previous message.
+fn cause_chain(e: &dyn std::error::Error) -> String {
+    let mut last = format!("{}", e);
+    let mut msg = last.clone();
+    let mut cause = e.source();
+    while let Some(c) = cause {
+        let next = format!("{}", c);
+        if next != last {
+            msg.push_str(&format!(", caused by: {}", next));
+        }
+        last = next;
+        cause = c.source();
+    }
+    msg
+}
 
 #[derive(Debug)]
 #[allow(non_camel_case_types)]
@@ -17,6 +73,71 @@ pub enum FallError {
     IO_ERROR(Error),
     HTTP_ERROR(StatusCode, Option<Box<dyn std::error::Error>>),
     REMOTE_ERROR(StatusCode, String),
+    DETAIL_ERROR(Box<ErrorDetail>),
+    VALIDATION_ERROR(Box<ErrorDetail>),
+    NOT_FOUND(Box<ErrorDetail>),
+    CONFLICT(Box<ErrorDetail>),
+    FORBIDDEN(Box<ErrorDetail>),
+    TIMEOUT(Box<ErrorDetail>),
+    DEPENDENCY_ERROR(Box<ErrorDetail>),
+    INTERNAL_ERROR(Box<ErrorDetail>),
+}
+
+/// Error detail.
+///
+/// Machine-readable code and extra members of an error response.
+#[derive(Debug)]
+pub struct ErrorDetail {
+    status: StatusCode,
+    message: String,
+    code: Option<String>,
+    errors: Vec<FieldError>,
+    extensions: Map<String, Value>,
+    source: Option<Box<dyn std::error::Error>>,
+    key: Option<String>,
+    args: Vec<(String, String)>,
+}
+
+impl ErrorDetail {
+    fn new(status: StatusCode, message: &str) -> Box<Self> {
+        Box::new(ErrorDetail {
+            status,
+            message: message.to_owned(),
+            code: None,
+            errors: vec![],
+            extensions: Map::new(),
+            source: None,
+            key: None,
+            args: vec![],
+        })
+    }
+
+    pub fn code(&self) -> Option<&str> {
+        self.code.as_deref()
+    }
+
+    pub fn errors(&self) -> &[FieldError] {
+        &self.errors
+    }
+
+    pub fn extensions(&self) -> &Map<String, Value> {
+        &self.extensions
+    }
+
+    pub fn message_key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+
+    /// Message translated for `accept_language`, if the key is in the catalog.
+    fn localize(&self, catalog: &Catalog, accept_language: Option<&str>) -> Option<String> {
+        catalog.translate(accept_language, self.key.as_ref()?, &self.args)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
 }
 
 impl FallError {
@@ -41,6 +162,137 @@ impl FallError {
     pub fn unauthorized(err: &str) -> Self {
         FallError::new(StatusCode::UNAUTHORIZED, err)
     }
+
+    /// Invalid input, 400.
+    pub fn validation(err: &str) -> Self {
+        FallError::VALIDATION_ERROR(ErrorDetail::new(StatusCode::BAD_REQUEST, err))
+    }
+
+    /// Resource not found, 404.
+    pub fn not_found(err: &str) -> Self {
+        FallError::NOT_FOUND(ErrorDetail::new(StatusCode::NOT_FOUND, err))
+    }
+
+    /// Resource state conflict, 409.
+    pub fn conflict(err: &str) -> Self {
+        FallError::CONFLICT(ErrorDetail::new(StatusCode::CONFLICT, err))
+    }
+
+    /// Authenticated but not allowed, 403.
+    pub fn forbidden(err: &str) -> Self {
+        FallError::FORBIDDEN(ErrorDetail::new(StatusCode::FORBIDDEN, err))
+    }
+
+    /// Operation timed out, 504.
+    pub fn timeout(err: &str) -> Self {
+        FallError::TIMEOUT(ErrorDetail::new(StatusCode::GATEWAY_TIMEOUT, err))
+    }
+
+    /// Dependency `name` failed, 502.
+    pub fn dependency(name: &str, err: &str) -> Self {
+        FallError::DEPENDENCY_ERROR(ErrorDetail::new(StatusCode::BAD_GATEWAY, err))
+            .with_extension("dependency", name)
+    }
+
+    /// Unexpected failure, 500.
+    pub fn internal(err: &str) -> Self {
+        FallError::INTERNAL_ERROR(ErrorDetail::new(StatusCode::INTERNAL_SERVER_ERROR, err))
+    }
+
+    fn detail_mut(&mut self) -> Option<&mut ErrorDetail> {
+        match self {
+            FallError::DETAIL_ERROR(d)
+            | FallError::VALIDATION_ERROR(d)
+            | FallError::NOT_FOUND(d)
+            | FallError::CONFLICT(d)
+            | FallError::FORBIDDEN(d)
+            | FallError::TIMEOUT(d)
+            | FallError::DEPENDENCY_ERROR(d)
+            | FallError::INTERNAL_ERROR(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    pub fn detail(&self) -> Option<&ErrorDetail> {
+        match self {
+            FallError::DETAIL_ERROR(d)
+            | FallError::VALIDATION_ERROR(d)
+            | FallError::NOT_FOUND(d)
+            | FallError::CONFLICT(d)
+            | FallError::FORBIDDEN(d)
+            | FallError::TIMEOUT(d)
+            | FallError::DEPENDENCY_ERROR(d)
+            | FallError::INTERNAL_ERROR(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    /// Keep the variant if it has a detail, otherwise convert into `DETAIL_ERROR`.
+    fn map_detail<F: FnOnce(&mut ErrorDetail)>(mut self, f: F) -> Self {
+        if let Some(d) = self.detail_mut() {
+            f(d);
+            return self;
+        }
+        let mut d = ErrorDetail::new(self.status_code(), &format!("{}", self));
+        d.source = match self {
+            FallError::IO_ERROR(e) => Some(Box::new(e)),
+            FallError::HTTP_ERROR(_, e) => e,
+            _ => None,
+        };
+        f(&mut d);
+        FallError::DETAIL_ERROR(d)
+    }
+
+    /// Machine-readable error code for clients to branch on.
+    pub fn with_code(self, code: &str) -> Self {
+        self.map_detail(|d| d.code = Some(code.to_owned()))
+    }
+
+    /// Underlying cause, returned by `source()`.
+    pub fn with_source<E: std::error::Error + 'static>(self, source: E) -> Self {
+        self.map_detail(|d| d.source = Some(Box::new(source)))
+    }
+
+    pub fn with_field_error(self, field: &str, message: &str) -> Self {
+        self.map_detail(|d| {
+            d.errors.push(FieldError {
+                field: field.to_owned(),
+                message: message.to_owned(),
+            })
+        })
+    }
+
+    /// Extra member of the error body, members like `status` or `type` are rejected.
+    pub fn with_extension<V: Serialize>(self, key: &str, value: V) -> Self {
+        if RESERVED_MEMBERS.contains(&key) {
+            warn!("Reserved error member {} rejected", key);
+            return self;
+        }
+        let value = match serde_json::to_value(value) {
+            Ok(value) => value,
+            Err(e) => {
+                warn!("Error member {} skipped: {}", key, e);
+                return self;
+            }
+        };
+        self.map_detail(|d| {
+            d.extensions.insert(key.to_owned(), value);
+        })
+    }
+
+    /// Catalog key of the message, the message is kept as fallback.
+    pub fn with_message_key(self, key: &str) -> Self {
+        self.map_detail(|d| d.key = Some(key.to_owned()))
+    }
+
+    /// Argument replacing `{name}` in the translated message.
+    pub fn with_arg<V: Display>(self, name: &str, value: V) -> Self {
+        self.map_detail(|d| d.args.push((name.to_owned(), format!("{}", value))))
+    }
+
+    pub fn code(&self) -> Option<&str> {
+        self.detail().and_then(|d| d.code())
+    }
 }
 
 impl Display for FallError {
@@ -54,11 +306,33 @@ impl Display for FallError {
                 e.fmt(f)
             }
             FallError::REMOTE_ERROR(_, o) => o.fmt(f),
+            _ => self.detail().expect("Detail not found").fmt(f),
         }
     }
 }
 
-impl std::error::Error for FallError {}
+impl std::error::Error for FallError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FallError::IO_ERROR(e) => Some(e),
+            FallError::HTTP_ERROR(_, _) => None,
+            FallError::REMOTE_ERROR(_, _) => None,
+            _ => self.detail().and_then(|d| d.source.as_deref()),
+        }
+    }
+}
+
+impl Display for ErrorDetail {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        self.message.fmt(f)
+    }
+}
+
+impl std::error::Error for ErrorDetail {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source.as_deref()
+    }
+}
 
 impl From<FallError> for Error {
     fn from(fe: FallError) -> Self {
@@ -72,16 +346,46 @@ impl From<FallError> for Error {
                 error!("{:?} - {}", e, s);
                 ErrorKind::InvalidData.into()
             }
+            fe => {
+                error!("{:?}", fe);
+                Error::new(ErrorKind::InvalidInput, cause_chain(&fe))
+            }
         }
     }
 }
 
 #[derive(Serialize)]
-pub struct ErrorBody {
+pub struct ErrorBody<'a> {
     #[serde(skip_serializing_if = "Option::is_none")]
     trace_id: Option<String>,
     status: u16,
     message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'a str>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    errors: &'a [FieldError],
+    #[serde(flatten)]
+    extensions: Option<&'a Map<String, Value>>,
+}
+
+/// Problem details, RFC 7807.
+#[derive(Serialize)]
+pub struct ProblemBody<'a> {
+    #[serde(rename = "type")]
+    problem_type: String,
+    title: &'a str,
+    status: u16,
+    detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trace_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'a str>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    errors: &'a [FieldError],
+    #[serde(flatten)]
+    extensions: Option<&'a Map<String, Value>>,
 }
 
 impl ResponseError for FallError {
@@ -90,24 +394,92 @@ impl ResponseError for FallError {
             FallError::IO_ERROR(_) => StatusCode::INTERNAL_SERVER_ERROR,
             FallError::HTTP_ERROR(s, _) => *s,
             FallError::REMOTE_ERROR(s, _) => *s,
+            _ => self.detail().expect("Detail not found").status,
         }
     }
 
+    /// Log server errors in full, then render without request values, see `ErrorContext`.
     fn error_response(&self) -> Response {
+        if self.status_code().is_server_error() {
+            error!("{}", cause_chain(self));
+        }
+        self.render(&ErrorContext::default())
+    }
+}
+
+/// Error context.
+///
+/// Settings and request values of an error body, built by `FallTransform` for each request.
+#[derive(Clone, Default)]
+pub(crate) struct ErrorContext {
+    pub(crate) config: Arc<ErrorConfig>,
+    pub(crate) trace_id: Option<String>,
+    pub(crate) path: Option<String>,
+    pub(crate) accept_language: Option<String>,
+}
+
+impl FallError {
+    /// Response body for the request of `ctx`, server errors get a generic message.
+    pub(crate) fn render(&self, ctx: &ErrorContext) -> Response {
         let mut resp = Response::new(self.status_code());
+        // Upstream client errors are passed through, server errors are hidden below.
+        if let FallError::REMOTE_ERROR(s, m) = self {
+            if !s.is_server_error() {
+                resp.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    header::HeaderValue::from_static("application/json"),
+                );
+                return resp.set_body(Body::from(m));
+            }
+        }
+        let status = resp.status();
+        let message = if status.is_server_error() {
+            if ctx.config.show_details {
+                format!("{}", self)
+            } else {
+                status.canonical_reason().unwrap_or("").to_owned()
+            }
+        } else {
+            self.detail()
+                .and_then(|d| d.localize(&ctx.config.catalog, ctx.accept_language.as_deref()))
+                .unwrap_or_else(|| format!("{}", self))
+        };
+        let detail = self.detail();
+        let code = detail.and_then(|d| d.code());
+        let errors = detail.map(|d| d.errors()).unwrap_or(&[]);
+        let extensions = detail.map(|d| d.extensions());
+        let (content_type, body) = if ctx.config.problem_json {
+            let body = ProblemBody {
+                problem_type: match code {
+                    Some(code) => format!("urn:problem-type:{}", code),
+                    _ => "about:blank".to_owned(),
+                },
+                title: status.canonical_reason().unwrap_or(""),
+                status: status.as_u16(),
+                detail: message,
+                instance: ctx.path.clone(),
+                trace_id: ctx.trace_id.clone(),
+                code,
+                errors,
+                extensions,
+            };
+            ("application/problem+json", serde_json::to_string(&body))
+        } else {
+            let body = ErrorBody {
+                trace_id: ctx.trace_id.clone(),
+                status: status.as_u16(),
+                message,
+                code,
+                errors,
+                extensions,
+            };
+            ("application/json", serde_json::to_string(&body))
+        };
         resp.headers_mut().insert(
             header::CONTENT_TYPE,
-            header::HeaderValue::from_static("application/json"),
+            header::HeaderValue::from_static(content_type),
         );
-        if let FallError::REMOTE_ERROR(_, m) = self {
-            return resp.set_body(Body::from(m));
-        }
-        let body = ErrorBody {
-            trace_id: fall_log::current_trace_id(),
-            status: resp.status().as_u16(),
-            message: format!("{}", self),
-        };
-        if let Ok(v) = serde_json::to_string(&body) {
+        if let Ok(v) = body {
             return resp.set_body(Body::from(v));
         }
         *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
@@ -115,9 +487,43 @@ impl ResponseError for FallError {
     }
 }
 
+/// Error handler of `JsonConfig`.
+pub(crate) fn json_error(e: JsonPayloadError, _: &HttpRequest) -> actix_web::Error {
+    let (status, code) = match e {
+        JsonPayloadError::Overflow => (StatusCode::PAYLOAD_TOO_LARGE, "PAYLOAD_TOO_LARGE"),
+        JsonPayloadError::ContentType => {
+            (StatusCode::UNSUPPORTED_MEDIA_TYPE, "UNSUPPORTED_MEDIA_TYPE")
+        }
+        _ => (StatusCode::BAD_REQUEST, "INVALID_JSON"),
+    };
+    FallError::new(status, &format!("{}", e))
+        .with_code(code)
+        .into()
+}
+
+/// Error handler of `QueryConfig`.
+pub(crate) fn query_error(e: QueryPayloadError, _: &HttpRequest) -> actix_web::Error {
+    FallError::validation(&format!("{}", e))
+        .with_code("INVALID_QUERY")
+        .into()
+}
+
+/// Error handler of `PathConfig`.
+pub(crate) fn path_error(e: PathError, _: &HttpRequest) -> actix_web::Error {
+    FallError::not_found(&format!("{}", e))
+        .with_code("INVALID_PATH")
+        .into()
+}
+
 impl From<config::ConfigError> for FallError {
     fn from(e: config::ConfigError) -> Self {
-        FallError::IO_ERROR(Error::new(ErrorKind::InvalidData, e))
+        FallError::internal("Invalid config").with_source(e)
+    }
+}
+
+impl From<ValidationErrors> for FallError {
+    fn from(e: ValidationErrors) -> Self {
+        FallError::internal(&e.to_string()).with_source(e)
     }
 }
 
@@ -148,20 +554,111 @@ impl From<awc::error::JsonPayloadError> for FallError {
 #[cfg(feature = "r2d2")]
 impl From<r2d2::Error> for FallError {
     fn from(e: r2d2::Error) -> Self {
-        FallError::IO_ERROR(Error::new(ErrorKind::InvalidInput, e))
+        FallError::dependency("pool", "Get connection failed").with_source(e)
     }
 }
 
 #[cfg(feature = "database")]
 impl From<diesel::result::Error> for FallError {
     fn from(e: diesel::result::Error) -> Self {
-        FallError::IO_ERROR(Error::new(ErrorKind::InvalidData, e))
+        FallError::dependency("database", "Database query failed").with_source(e)
     }
 }
 
 #[cfg(feature = "redis")]
 impl From<r2d2_redis::redis::RedisError> for FallError {
     fn from(e: r2d2_redis::redis::RedisError) -> Self {
-        FallError::IO_ERROR(Error::new(ErrorKind::InvalidData, e))
+        FallError::dependency("redis", "Redis command failed").with_source(e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::*;
+    use std::error::Error as StdError;
+
+    #[test]
+    fn test_error() {
+        let io = Error::new(ErrorKind::TimedOut, "read timed out");
+        let e = FallError::dependency("redis", "Redis unavailable")
+            .with_code("REDIS_DOWN")
+            .with_source(io);
+        assert_eq!(StatusCode::BAD_GATEWAY, e.status_code());
+        assert_eq!("Redis unavailable", format!("{}", e));
+        assert_eq!(Some("REDIS_DOWN"), e.code());
+        assert_eq!("read timed out", format!("{}", e.source().unwrap()));
+        assert!(matches!(e, FallError::DEPENDENCY_ERROR(_)));
+
+        let e = FallError::bad_request("Invalid id").with_code("ID_INVALID");
+        assert_eq!(StatusCode::BAD_REQUEST, e.status_code());
+        assert!(matches!(e, FallError::DETAIL_ERROR(_)));
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            FallError::not_found("").status_code()
+        );
+        assert_eq!(StatusCode::CONFLICT, FallError::conflict("").status_code());
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            FallError::forbidden("").status_code()
+        );
+        assert_eq!(
+            StatusCode::GATEWAY_TIMEOUT,
+            FallError::timeout("").status_code()
+        );
+        assert_eq!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            FallError::internal("").status_code()
+        );
+
+        let e = FallError::conflict("")
+            .with_extension("status", 200)
+            .with_extension("retry", true);
+        let extensions = e.detail().unwrap().extensions();
+        assert!(!extensions.contains_key("status"));
+        assert_eq!(Some(&Value::Bool(true)), extensions.get("retry"));
+        // Maps with non string keys are not valid json.
+        let mut invalid = std::collections::BTreeMap::new();
+        invalid.insert(vec![1u8], 1);
+        let e = FallError::conflict("").with_extension("invalid", invalid);
+        assert!(e.detail().unwrap().extensions().is_empty());
+
+        let mut errors = ValidationErrors::new();
+        errors.add("tls.cert", "file not found");
+        let e: FallError = errors.into();
+        assert!(matches!(e, FallError::INTERNAL_ERROR(_)));
+        assert_eq!("Invalid config: tls.cert file not found", format!("{}", e));
+        assert!(e.source().is_some());
+
+        let e: FallError = config::ConfigError::NotFound("redis.url".to_owned()).into();
+        assert!(matches!(e, FallError::INTERNAL_ERROR(_)));
+        assert!(format!("{}", e.source().unwrap()).contains("redis.url"));
+        let e = FallError::IO_ERROR(Error::new(ErrorKind::TimedOut, "read timed out"));
+        assert_eq!("read timed out", format!("{}", e.source().unwrap()));
+        assert_eq!("read timed out", cause_chain(&e));
+    }
+
+    fn body_of(e: FallError) -> String {
+        match e.error_response().body().as_ref() {
+            Some(Body::Bytes(b)) => String::from_utf8_lossy(b).into_owned(),
+            _ => String::new(),
+        }
+    }
+
+    #[test]
+    fn test_remote_error() {
+        let capture = CaptureLog::new();
+        let _guard = capture.set_default();
+        let body = body_of(FallError::remote_err(503, "db.internal:5432 refused"));
+        assert!(body.contains("\"message\":\"Service Unavailable\""));
+        assert!(!body.contains("db.internal"));
+        capture.assert_logged(
+            &RecordQuery::new()
+                .level(Level::ERROR)
+                .message("db.internal:5432 refused"),
+        );
+        assert_eq!(
+            "{\"code\":1}",
+            body_of(FallError::remote_err(409, serde_json::json!({"code": 1})))
+        );
     }
 }
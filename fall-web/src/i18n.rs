@@ -0,0 +1,148 @@
+use crate::env::flatten;
+use config::Config;
+use config::ConfigError;
+use config::File;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Message catalog.
+///
+/// One `{locale}.toml` file per locale, mapping message keys to templates
+/// like `order.not_found = "Order {id} not found"`.
+#[derive(Debug, Default)]
+pub struct Catalog {
+    default_locale: String,
+    messages: HashMap<String, BTreeMap<String, String>>,
+}
+
+impl Catalog {
+    pub fn new(default_locale: &str) -> Self {
+        Catalog {
+            default_locale: default_locale.to_lowercase(),
+            messages: HashMap::new(),
+        }
+    }
+
+    /// Load every `*.toml` in `dir`, a missing dir gives an empty catalog.
+    pub fn load(dir: &str, default_locale: &str) -> Result<Self, ConfigError> {
+        let mut catalog = Catalog::new(default_locale);
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            _ => return Ok(catalog),
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("toml") {
+                continue;
+            }
+            if let Some(locale) = path.file_stem().and_then(|s| s.to_str()) {
+                catalog.add_file(locale, &path)?;
+            }
+        }
+        Ok(catalog)
+    }
+
+    fn add_file(&mut self, locale: &str, path: &Path) -> Result<(), ConfigError> {
+        let mut config = Config::new();
+        config.merge(File::from(path))?;
+        let mut messages = BTreeMap::new();
+        flatten("", config.cache, &mut messages);
+        self.messages
+            .entry(locale.to_lowercase())
+            .or_default()
+            .extend(messages);
+        Ok(())
+    }
+
+    pub fn add_message(&mut self, locale: &str, key: &str, template: &str) {
+        self.messages
+            .entry(locale.to_lowercase())
+            .or_default()
+            .insert(key.to_owned(), template.to_owned());
+    }
+
+    /// Translate `key` to the best locale of `accept_language`, then the default locale.
+    pub fn translate(
+        &self,
+        accept_language: Option<&str>,
+        key: &str,
+        args: &[(String, String)],
+    ) -> Option<String> {
+        let mut locales = accept_language
+            .map(parse_accept_language)
+            .unwrap_or_default();
+        locales.push(self.default_locale.clone());
+        for locale in locales {
+            let primary = locale.split('-').next().unwrap_or("");
+            let template = self
+                .messages
+                .get(&locale)
+                .or_else(|| self.messages.get(primary))
+                .and_then(|m| m.get(key));
+            if let Some(t) = template {
+                let mut msg = t.clone();
+                for (k, v) in args {
+                    msg = msg.replace(&format!("{{{}}}", k), v);
+                }
+                return Some(msg);
+            }
+        }
+        None
+    }
+}
+
+/// Locales ordered by quality, `zh-CN,zh;q=0.9,en;q=0.8`.
+fn parse_accept_language(value: &str) -> Vec<String> {
+    let mut locales: Vec<(String, f32)> = value
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let tag = parts.next()?.trim().to_lowercase();
+            let q = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .and_then(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            if tag.is_empty() || tag == "*" || q <= 0.0 {
+                return None;
+            }
+            Some((tag, q))
+        })
+        .collect();
+    locales.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    locales.into_iter().map(|(tag, _)| tag).collect()
+}
+
+/// Load the catalog from `application.i18n.dir` (default `i18n`),
+/// falling back to `application.i18n.default_locale` (default `en`).
+pub(crate) fn load(config: &Config) -> Result<Catalog, ConfigError> {
+    let dir = config
+        .get_str("application.i18n.dir")
+        .unwrap_or_else(|_| "i18n".to_owned());
+    let default_locale = config
+        .get_str("application.i18n.default_locale")
+        .unwrap_or_else(|_| "en".to_owned());
+    Catalog::load(&dir, &default_locale)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::i18n::*;
+
+    #[test]
+    fn test_translate() {
+        assert_eq!(
+            vec!["zh-cn", "en", "zh"],
+            parse_accept_language("zh;q=0.5, zh-CN, en;q=0.8, *;q=0.1")
+        );
+        let mut catalog = Catalog::new("en");
+        catalog.add_message("en", "order.invalid", "Invalid order {id}");
+        catalog.add_message("zh", "order.invalid", "订单 {id} 无效");
+        let args = vec![("id".to_owned(), "7".to_owned())];
+        let t = |lang| catalog.translate(lang, "order.invalid", &args);
+        assert_eq!(Some("订单 7 无效".to_owned()), t(Some("zh-CN,en;q=0.8")));
+        assert_eq!(Some("Invalid order 7".to_owned()), t(Some("fr")));
+        assert_eq!(Some("Invalid order 7".to_owned()), t(None));
+        assert_eq!(None, catalog.translate(None, "missing", &args));
+    }
+}
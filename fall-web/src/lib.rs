@@ -1,47 +1,90 @@
 use crate::endpoints::endpoints;
 use crate::endpoints::HealthList;
+use crate::env::PropertySources;
+use crate::reload::watch;
+use crate::reload::RefreshList;
+use crate::reload::SharedConfig;
+use crate::section::Binder;
+#[cfg(any(feature = "redis", feature = "database"))]
+use crate::section::ConfigSection;
+use crate::section::ConfigSections;
+#[cfg(any(feature = "database", feature = "redis"))]
+use crate::section::ValidationErrors;
+use crate::shutdown::graceful_stop;
+use crate::shutdown::Readiness;
+use crate::shutdown::ShutdownHooks;
+use crate::web::add_log_fields;
 use crate::web::from_req;
+use actix_web::body::Body;
+use actix_web::body::BodySize;
 use actix_web::dev::ServiceRequest;
 use actix_web::dev::ServiceResponse;
+use actix_web::http::header;
 use actix_web::web::Data;
+use actix_web::web::JsonConfig;
+use actix_web::web::PathConfig;
+use actix_web::web::PayloadConfig;
+use actix_web::web::QueryConfig;
 use actix_web::web::ServiceConfig;
 use actix_web::App;
 use actix_web::Error;
 use actix_web::HttpRequest;
 use actix_web::HttpServer;
+use fall_log::capture_panics;
+use fall_log::info;
 use fall_log::span;
 use fall_log::FallLog;
+use fall_log::Regex;
 use futures_util::future::FutureExt;
 use futures_util::future::LocalBoxFuture;
 use serde::{Deserialize, Serialize};
 use std::env::var;
+use std::sync::Arc;
 use std::time::Duration;
 
 pub use actix_http::body::MessageBody;
 pub use actix_service::ServiceFactory;
 pub use actix_web::http::StatusCode;
+pub use error::ErrorConfig;
+pub use error::ErrorDetail;
 pub use error::FallError;
+pub use error::FieldError;
 
 #[cfg(feature = "database")]
 use crate::database::DatabaseConfig;
+use crate::listener::parse_listeners;
+use crate::listener::Listener;
 #[cfg(feature = "redis")]
 use crate::redis::RedisConfig;
+#[cfg(feature = "tls")]
+use crate::tls::TlsConfig;
 
 pub use client::*;
 pub use config::Config;
+pub use env::ConfigLoader;
 pub use web::{DefaultRequestHandler, FallTransform};
 
 #[cfg(feature = "database")]
 pub mod database;
 #[cfg(feature = "redis")]
 pub mod redis;
+#[cfg(feature = "tls")]
+pub mod tls;
 
 pub mod endpoints;
+pub mod env;
+pub mod i18n;
+pub mod listener;
+pub mod reload;
+pub mod section;
+pub mod shutdown;
+pub mod test;
 
 mod client;
 mod error;
 mod web;
 
+#[cfg(any(feature = "database", feature = "redis"))]
 #[derive(Debug, Clone, Deserialize)]
 struct PoolConfig {
     max_size: Option<u32>,
@@ -51,6 +94,19 @@ struct PoolConfig {
     connection_timeout: Option<Duration>,
 }
 
+#[cfg(any(feature = "database", feature = "redis"))]
+impl PoolConfig {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        let max_size = self.max_size.unwrap_or(10);
+        if max_size == 0 {
+            errors.add("pool.max_size", "must be greater than 0");
+        }
+        if self.min_idle.map(|m| m > max_size).unwrap_or(false) {
+            errors.add("pool.min_idle", "must not be greater than max_size");
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize)]
 pub struct Application {
     name: String,
@@ -76,7 +132,7 @@ impl Default for Application {
 
 pub trait RequestHandler {
     fn new_span(&self, req: &ServiceRequest) -> span::Span {
-        from_req(req).into()
+        from_req(req).request_span()
     }
 
     fn pre_request<'a>(
@@ -86,10 +142,42 @@ pub trait RequestHandler {
         async move { Ok(()) }.boxed_local()
     }
 
-    fn post_response<B>(&self, res: ServiceResponse<B>) -> ServiceResponse<B> {
+    /// Render framework errors, like 404, 405 or 413, as `FallError` json bodies.
+    ///
+    /// Responses built by handlers are kept, unless they are empty 404 or 405.
+    fn post_response<B: MessageBody>(&self, res: ServiceResponse<B>) -> ServiceResponse<B> {
         let status = res.status();
-        if status == StatusCode::NOT_FOUND {
-            return res.error_response(FallError::HTTP_ERROR(status, None));
+        if !status.is_client_error() && !status.is_server_error() {
+            return res;
+        }
+        let is_json = res
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.contains("json"))
+            .unwrap_or(false);
+        if is_json {
+            return res;
+        }
+        let err = match res.response().error() {
+            Some(e) => FallError::new(status, &format!("{}", e)),
+            // Default responses of unmatched routes and methods.
+            _ if (status == StatusCode::NOT_FOUND || status == StatusCode::METHOD_NOT_ALLOWED)
+                && matches!(
+                    res.response().body().size(),
+                    BodySize::Empty | BodySize::Sized(0)
+                ) =>
+            {
+                FallError::HTTP_ERROR(status, None)
+            }
+            _ => return res,
+        };
+        let headers = res.headers().clone();
+        let mut res = res.error_response(err);
+        for (name, value) in headers.iter() {
+            if name != header::CONTENT_TYPE && name != header::CONTENT_LENGTH {
+                res.headers_mut().append(name.clone(), value.clone());
+            }
         }
         res
     }
@@ -107,13 +195,21 @@ pub trait RequestHelper {
         self.get_data::<Application>()
             .expect("Application should exists")
     }
+    /// Latest config, reflecting reloads unlike the startup `Data<Config>`.
     fn get_config(&self) -> Data<Config> {
-        self.get_data::<Config>().expect("Config should exists")
+        self.get_data::<SharedConfig>()
+            .expect("Config should exists")
+            .get()
+            .into()
     }
 
     fn get<'d, T: Deserialize<'d>>(&self, key: &str) -> Result<T, FallError> {
         Ok(self.get_config().get(key)?)
     }
+
+    /// Client certificate verified by mutual tls.
+    #[cfg(feature = "tls")]
+    fn get_client_certificate(&self) -> Option<tls::ClientCertificate>;
 }
 
 impl RequestHelper for ServiceRequest {
@@ -122,7 +218,13 @@ impl RequestHelper for ServiceRequest {
     }
 
     fn get_data<T: 'static>(&self) -> Option<Data<T>> {
-        self.app_data::<T>()
+        self.app_data::<Data<T>>().cloned()
+    }
+
+    #[cfg(feature = "tls")]
+    fn get_client_certificate(&self) -> Option<tls::ClientCertificate> {
+        self.get_data::<tls::ClientCertificates>()?
+            .get(self.peer_addr()?, self.app_config().local_addr())
     }
 }
 
@@ -132,8 +234,27 @@ impl RequestHelper for HttpRequest {
     }
 
     fn get_data<T: 'static>(&self) -> Option<Data<T>> {
-        self.app_data::<Data<T>>().map(Clone::clone)
+        self.app_data::<Data<T>>().cloned()
+    }
+
+    #[cfg(feature = "tls")]
+    fn get_client_certificate(&self) -> Option<tls::ClientCertificate> {
+        self.get_data::<tls::ClientCertificates>()?
+            .get(self.peer_addr()?, self.app_config().local_addr())
+    }
+}
+
+/// Read a list, or a comma separated string.
+fn get_list(config: &Config, key: &str) -> Option<Vec<String>> {
+    if let Ok(v) = config.get::<Vec<String>>(key) {
+        return Some(v);
     }
+    config.get::<String>(key).ok().map(|v| {
+        v.split(',')
+            .map(|s| s.trim().to_owned())
+            .filter(|s| !s.is_empty())
+            .collect()
+    })
 }
 
 pub trait FallServer: Clone + Send + Sync {
@@ -144,6 +265,113 @@ pub trait FallServer: Clone + Send + Sync {
         String::from("0.0.0.0:8080")
     }
 
+    /// Listeners, `application.server.listeners`, defaults to `get_addr`.
+    ///
+    /// Accepts a list or a comma separated string of `host:port`, `unix:/path.sock`,
+    /// `fd:N` and `systemd`, see [`Listener`](listener/enum.Listener.html).
+    fn get_listeners(&self) -> Vec<String> {
+        get_list(self.get_config(), "application.server.listeners")
+            .unwrap_or_else(|| vec![self.get_addr()])
+    }
+
+    /// Worker threads, `application.server.workers`, defaults to the number of CPUs.
+    fn get_workers(&self) -> Option<usize> {
+        self.get_config().get("application.server.workers").ok()
+    }
+
+    /// Pending connections queue size, `application.server.backlog`.
+    fn get_backlog(&self) -> Option<i32> {
+        self.get_config().get("application.server.backlog").ok()
+    }
+
+    /// Concurrent connections per worker, `application.server.max_connections`.
+    fn get_max_connections(&self) -> Option<usize> {
+        self.get_config()
+            .get("application.server.max_connections")
+            .ok()
+    }
+
+    /// Keep-alive seconds, `application.server.keep_alive`.
+    fn get_keep_alive(&self) -> Option<usize> {
+        self.get_config().get("application.server.keep_alive").ok()
+    }
+
+    /// Milliseconds to receive the request head, `application.server.client_timeout`.
+    fn get_client_timeout(&self) -> Option<u64> {
+        self.get_config()
+            .get("application.server.client_timeout")
+            .ok()
+    }
+
+    /// Seconds to drain in-flight requests on shutdown, `application.server.shutdown_timeout`.
+    fn get_shutdown_timeout(&self) -> u64 {
+        self.get_config()
+            .get("application.server.shutdown_timeout")
+            .unwrap_or(30)
+    }
+
+    /// Seconds to report not ready before stopping on shutdown, `application.shutdown.pre_stop`.
+    fn get_pre_stop(&self) -> u64 {
+        self.get_config()
+            .get("application.shutdown.pre_stop")
+            .unwrap_or(0)
+    }
+
+    /// Max bytes of request payload, `application.server.max_payload_size`.
+    fn get_max_payload_size(&self) -> Option<usize> {
+        self.get_config()
+            .get("application.server.max_payload_size")
+            .ok()
+    }
+
+    /// Max bytes of json request body, `application.server.max_json_size`.
+    fn get_max_json_size(&self) -> Option<usize> {
+        self.get_config()
+            .get("application.server.max_json_size")
+            .ok()
+    }
+
+    /// Trace headers added to every response, `application.trace.response_headers`,
+    /// defaults to `X-Request-Id`, `X-B3-TraceId` and `traceresponse`.
+    fn get_response_headers(&self) -> Vec<String> {
+        get_list(self.get_config(), "application.trace.response_headers").unwrap_or_else(|| {
+            vec![
+                "X-Request-Id".to_owned(),
+                "X-B3-TraceId".to_owned(),
+                "traceresponse".to_owned(),
+            ]
+        })
+    }
+
+    /// Span fields set from each request and printed by `FallLog`, `application.log.fields`,
+    /// like `tenant=header:X-Tenant-Id`, `method`, `path` or `client_ip`.
+    fn get_log_fields(&self) -> Vec<String> {
+        get_list(self.get_config(), "application.log.fields").unwrap_or_default()
+    }
+
+    /// Fields masked in logs, `application.log.redact.fields`,
+    /// defaults to `password` and `authorization`.
+    fn get_redact_fields(&self) -> Vec<String> {
+        get_list(self.get_config(), "application.log.redact.fields")
+            .unwrap_or_else(|| vec!["password".to_owned(), "authorization".to_owned()])
+    }
+
+    /// Patterns masked in log messages, `application.log.redact.patterns`.
+    fn get_redact_patterns(&self) -> Result<Vec<Regex>, config::ConfigError> {
+        let patterns = self
+            .get_config()
+            .get::<Vec<String>>("application.log.redact.patterns")
+            .unwrap_or_default();
+        patterns
+            .iter()
+            .map(|p| {
+                Regex::new(p).map_err(|e| {
+                    config::ConfigError::Message(format!("Invalid redact pattern {}: {}", p, e))
+                })
+            })
+            .collect()
+    }
+
     fn new_request_handler(&self) -> Self::H;
 
     fn new_log(&self) -> FallLog<Self::W>;
@@ -156,18 +384,49 @@ pub trait FallServer: Clone + Send + Sync {
 
     fn get_config(&self) -> &Config;
 
+    fn get_property_sources(&self) -> PropertySources {
+        PropertySources::from_config(self.get_config())
+    }
+
+    /// Config and its sources to override keys in, used by `TestApp::set`.
+    fn config_mut(&mut self) -> Option<(&mut Config, &mut PropertySources)> {
+        None
+    }
+
+    /// Loader used to reload config, `None` disables reloading.
+    fn get_config_loader(&self) -> Option<ConfigLoader> {
+        None
+    }
+
+    fn refresh_listeners(&self) -> RefreshList {
+        RefreshList::new()
+    }
+
     fn health_check(&self) -> HealthList {
         HealthList::new()
     }
 
+    /// Hooks run after the server stopped, before the log is flushed.
+    ///
+    /// Redis and database pools have no close hook, r2d2 cannot close a shared pool,
+    /// their connections are released once the server and its workers are dropped.
+    fn shutdown_hooks(&self) -> ShutdownHooks {
+        ShutdownHooks::new()
+    }
+
+    /// Typed config sections, validated at startup and injected as `Data<T>`.
+    fn config_sections(&self) -> ConfigSections {
+        ConfigSections::new()
+    }
+
     #[cfg(feature = "redis")]
     fn get_redis(&self) -> Result<redis::RedisConn, FallError> {
-        self.get_config().get::<RedisConfig>("redis")?.init()
+        section::load_section::<RedisConfig>(self.get_config())?.init()
     }
 
     #[cfg(feature = "database")]
     fn get_database(&self) -> Result<database::DatabaseConn, FallError> {
-        self.get_config().get::<DatabaseConfig>("database")?.init()
+        section::load_section::<DatabaseConfig>(self.get_config())?.init()
     }
 
     fn config<T, B>(&self, _client: FallClient, app: App<T, B>) -> App<T, B>
@@ -189,35 +448,58 @@ pub trait FallServer: Clone + Send + Sync {
 pub struct DefaultFallServer {
     app: Application,
     config: Config,
+    sources: PropertySources,
+    loader: Option<ConfigLoader>,
 }
 
 impl DefaultFallServer {
     pub fn new(config: Config, app: Application) -> Self {
-        DefaultFallServer { app, config }
+        let sources = PropertySources::from_config(&config);
+        DefaultFallServer {
+            app,
+            config,
+            sources,
+            loader: None,
+        }
     }
-}
 
-fn set_config(config: &mut Config, app: &mut Application) -> Result<(), config::ConfigError> {
-    config
-        .set_default("redis.url", "redis://127.0.0.1/0")?
-        .set_default("database.url", "postgres://postgres@127.0.0.1/postgres")?
-        .merge(config::Environment::new())?
-        .merge(config::File::with_name("app").required(false))?;
-    if let Ok(name) = config.get::<String>("application.name") {
-        app.name = name;
-    }
-    if let Ok(version) = config.get::<String>("application.version") {
-        app.version = version;
+    pub fn load(loader: ConfigLoader, app: Application) -> Result<Self, config::ConfigError> {
+        let mut app = app;
+        let (config, sources) = load_config(&loader)?;
+        if let Ok(name) = config.get::<String>("application.name") {
+            app.name = name;
+        }
+        if let Ok(version) = config.get::<String>("application.version") {
+            app.version = version;
+        }
+        Ok(DefaultFallServer {
+            app,
+            config,
+            sources,
+            loader: Some(loader),
+        })
     }
-    Ok(())
+}
+
+pub(crate) fn load_config(
+    loader: &ConfigLoader,
+) -> Result<(Config, PropertySources), config::ConfigError> {
+    let mut config = Config::new();
+    let mut sources = PropertySources::new();
+    sources.set_default(&mut config, "redis.url", "redis://127.0.0.1/0")?;
+    sources.set_default(
+        &mut config,
+        "database.url",
+        "postgres://postgres@127.0.0.1/postgres",
+    )?;
+    loader.load(&mut config, &mut sources)?;
+    sources.resolve_secrets(&mut config)?;
+    Ok((config, sources))
 }
 
 impl Default for DefaultFallServer {
     fn default() -> Self {
-        let mut app = Application::default();
-        let mut config = Config::new();
-        set_config(&mut config, &mut app).unwrap();
-        DefaultFallServer { app, config }
+        DefaultFallServer::load(ConfigLoader::new(), Application::default()).unwrap()
     }
 }
 
@@ -250,44 +532,274 @@ impl FallServer for DefaultFallServer {
     fn get_config(&self) -> &Config {
         &self.config
     }
+
+    fn get_property_sources(&self) -> PropertySources {
+        self.sources.clone()
+    }
+
+    fn get_config_loader(&self) -> Option<ConfigLoader> {
+        self.loader.clone()
+    }
+
+    fn config_mut(&mut self) -> Option<(&mut Config, &mut PropertySources)> {
+        Some((&mut self.config, &mut self.sources))
+    }
 }
 
-pub async fn start<F, A>(config: F, app: A) -> std::io::Result<()>
+/// Shared state of every worker app.
+#[derive(Clone)]
+pub(crate) struct AppContext {
+    shared: SharedConfig,
+    readiness: Readiness,
+    error: ErrorConfig,
+    sections: Arc<Vec<Binder>>,
+    max_payload_size: Option<usize>,
+    max_json_size: Option<usize>,
+    #[cfg(feature = "tls")]
+    certs: tls::ClientCertificates,
+    #[cfg(feature = "redis")]
+    redis: Option<redis::RedisConn>,
+    #[cfg(feature = "database")]
+    db: Option<database::DatabaseConn>,
+}
+
+impl AppContext {
+    /// With `optional_pools`, pools are only initialized when their section is configured.
+    #[cfg_attr(
+        not(any(feature = "redis", feature = "database")),
+        allow(unused_variables)
+    )]
+    pub(crate) fn new<A: FallServer>(app: &A, optional_pools: bool) -> Result<Self, FallError> {
+        #[cfg(any(feature = "redis", feature = "database"))]
+        let configured = |key: &str| !optional_pools || app.get_config().get_table(key).is_ok();
+        // Invalid patterns are skipped by `configure_log`, fail here instead.
+        app.get_redact_patterns()?;
+        Ok(AppContext {
+            shared: SharedConfig::new(app.get_config().clone(), app.get_property_sources()),
+            readiness: Readiness::new(),
+            error: ErrorConfig::load(app.get_config())?,
+            sections: app.config_sections().bind(app.get_config())?,
+            max_payload_size: app.get_max_payload_size(),
+            max_json_size: app.get_max_json_size(),
+            #[cfg(feature = "tls")]
+            certs: tls::ClientCertificates::default(),
+            #[cfg(feature = "redis")]
+            redis: match configured(RedisConfig::KEY) {
+                true => Some(app.get_redis()?),
+                _ => None,
+            },
+            #[cfg(feature = "database")]
+            db: match configured(DatabaseConfig::KEY) {
+                true => Some(app.get_database()?),
+                _ => None,
+            },
+        })
+    }
+}
+
+/// Apply log fields and redaction from config to `log`.
+pub(crate) fn configure_log<A, W>(app: &A, log: FallLog<W>) -> FallLog<W>
 where
-    F: FnMut(&mut ServiceConfig) + Send + Clone + 'static,
+    A: FallServer,
+    W: std::io::Write + Send + 'static,
+{
+    let config = app.get_config();
+    let enabled = |key: &str| config.get::<bool>(key).unwrap_or(false);
+    let log = add_log_fields(log, &app.get_log_fields())
+        .redact_fields(app.get_redact_fields())
+        .redact_patterns(app.get_redact_patterns().unwrap_or_default())
+        .log_panics(enabled("application.log.panic_hook"))
+        .log_span_close(enabled("application.log.span_close"))
+        .log_baggage(enabled("application.log.baggage"));
+    // Keep secrets already masked by `new_log`.
+    log.secrets_updater()(app.get_property_sources().secrets());
+    log
+}
+
+/// Build the app served by each worker.
+pub(crate) fn new_app<F, A>(
+    app: &A,
+    ctx: &AppContext,
+    config: F,
+) -> App<
+    impl ServiceFactory<
+        Config = (),
+        Request = ServiceRequest,
+        Response = ServiceResponse<Body>,
+        Error = Error,
+        InitError = (),
+    >,
+    Body,
+>
+where
+    F: FnOnce(&mut ServiceConfig),
     A: FallServer + 'static,
 {
-    let _ = app.new_log().init();
-    let addr = app.get_addr();
+    let client = app.new_client();
+    let _app = app
+        .config(client.clone(), App::new())
+        .data(client)
+        // Startup config, reloads are only seen through `RequestHelper::get_config`.
+        .data(app.get_config().clone())
+        .data(app.get_app().clone())
+        .data(ctx.shared.clone())
+        .data(ctx.readiness.clone())
+        .data(ctx.error.clone());
+
+    #[cfg(feature = "tls")]
+    let _app = _app.data(ctx.certs.clone());
+    let mut check = app.health_check();
+    check.add_check("readiness", Box::new(ctx.readiness.clone()));
     #[cfg(feature = "redis")]
-    let redis = app.get_redis()?;
+    let _app = match &ctx.redis {
+        Some(redis) => {
+            check.add_check("redis", Box::new(redis.clone()));
+            _app.data(redis.clone())
+        }
+        _ => _app,
+    };
     #[cfg(feature = "database")]
-    let db = app.get_database()?;
-    HttpServer::new(move || {
-        let client = app.new_client();
-        let _app = app
-            .config(client.clone(), App::new())
-            .data(client)
-            .data(app.get_config().clone())
-            .data(app.get_app().clone());
-
-        #[allow(unused_mut)]
-        let mut check = app.health_check();
-        #[cfg(feature = "redis")]
-        let _app = _app.data(redis.clone());
-        #[cfg(feature = "redis")]
-        check.add_check("redis", Box::new(redis.clone()));
-        #[cfg(feature = "database")]
-        let _app = _app.data(db.clone());
-        #[cfg(feature = "database")]
-        check.add_check("database", Box::new(db.clone()));
-
-        _app.data(check)
-            .wrap(FallTransform::new(app.new_request_handler()))
-            .configure(endpoints)
-            .configure(config.clone())
-    })
-    .bind(addr)?
-    .run()
-    .await
+    let _app = match &ctx.db {
+        Some(db) => {
+            check.add_check("database", Box::new(db.clone()));
+            _app.data(db.clone())
+        }
+        _ => _app,
+    };
+
+    let _app = match ctx.max_payload_size {
+        Some(size) => _app.app_data(PayloadConfig::new(size)),
+        _ => _app,
+    };
+    let json = JsonConfig::default().error_handler(error::json_error);
+    let _app = _app
+        .app_data(match ctx.max_json_size {
+            Some(size) => json.limit(size),
+            _ => json,
+        })
+        .app_data(QueryConfig::default().error_handler(error::query_error))
+        .app_data(PathConfig::default().error_handler(error::path_error));
+
+    let sections = ctx.sections.clone();
+    _app.data(check)
+        .configure(move |cfg| {
+            for b in sections.iter() {
+                b(cfg);
+            }
+        })
+        .wrap(
+            FallTransform::new(app.new_request_handler())
+                .response_headers(&app.get_response_headers())
+                .log_fields(&app.get_log_fields()),
+        )
+        .configure(endpoints)
+        .configure(config)
+}
+
+pub async fn start<F, A>(config: F, app: A) -> std::io::Result<()>
+where
+    F: FnMut(&mut ServiceConfig) + Send + Clone + 'static,
+    A: FallServer + 'static,
+{
+    let log = configure_log(&app, app.new_log());
+    let flush_log = log.flusher();
+    let update_secrets = log.secrets_updater();
+    let _ = log.init();
+    capture_panics();
+    let ctx = AppContext::new(&app, false)?;
+    // Inherited `fd:N` listeners are handed to the application by whoever starts it.
+    let listeners = unsafe { parse_listeners(&app.get_listeners()) }?;
+    #[cfg(feature = "tls")]
+    let tls = TlsConfig::load(app.get_config()).map_err(FallError::from)?;
+    #[cfg(feature = "tls")]
+    let app_addr = app.get_addr();
+    if let Some(loader) = app.get_config_loader() {
+        let mut listeners = app.refresh_listeners();
+        let shared = ctx.shared.clone();
+        // Secrets resolved by a reload are masked too, old ones are kept.
+        listeners.add_listener(Box::new(move |_: &Config| {
+            update_secrets(shared.get_sources().secrets())
+        }));
+        watch(loader, ctx.shared.clone(), listeners);
+    }
+    let readiness = ctx.readiness.clone();
+    let pre_stop = app.get_pre_stop();
+    let shutdown_timeout = app.get_shutdown_timeout();
+    let workers = app.get_workers();
+    let backlog = app.get_backlog();
+    let max_connections = app.get_max_connections();
+    let keep_alive = app.get_keep_alive();
+    let client_timeout = app.get_client_timeout();
+    let hooks = app.shutdown_hooks();
+    #[cfg(feature = "tls")]
+    let certs = ctx.certs.clone();
+    let mut server = HttpServer::new(move || new_app(&app, &ctx, config.clone()))
+        .shutdown_timeout(shutdown_timeout)
+        .disable_signals();
+    if let Some(v) = workers {
+        server = server.workers(v);
+    }
+    if let Some(v) = backlog {
+        server = server.backlog(v);
+    }
+    if let Some(v) = max_connections {
+        server = server.max_connections(v);
+    }
+    if let Some(v) = keep_alive {
+        server = server.keep_alive(v);
+    }
+    if let Some(v) = client_timeout {
+        server = server.client_timeout(v);
+    }
+    #[cfg(feature = "tls")]
+    let mut redirect = None;
+    #[cfg(feature = "tls")]
+    let tls_config = match &tls {
+        Some(tls) => {
+            if let Some(port) = tls.redirect_port() {
+                let addr = listeners
+                    .iter()
+                    .find_map(Listener::addr)
+                    .unwrap_or_else(|| app_addr.clone());
+                let https_port = addr.rsplit(':').next().and_then(|p| p.parse().ok());
+                let host = addr.rsplitn(2, ':').last().unwrap_or("0.0.0.0");
+                redirect = Some(tls::redirect_server(
+                    format!("{}:{}", host, port),
+                    https_port.unwrap_or(443),
+                )?);
+            }
+            server = server.on_connect(move |conn, _| certs.on_connect(conn));
+            Some(tls.server_config()?)
+        }
+        _ => None,
+    };
+    for listener in listeners {
+        info!("Listening on {}", listener);
+        server = match listener {
+            #[cfg(feature = "tls")]
+            Listener::Tcp(addr) if tls_config.is_some() => {
+                server.bind_rustls(addr, tls_config.clone().unwrap())?
+            }
+            #[cfg(feature = "tls")]
+            Listener::TcpFd(lst) if tls_config.is_some() => {
+                server.listen_rustls(lst, tls_config.clone().unwrap())?
+            }
+            Listener::Tcp(addr) => server.bind(addr)?,
+            Listener::TcpFd(lst) => server.listen(lst)?,
+            #[cfg(unix)]
+            Listener::Unix(path) => server.bind_uds(path)?,
+            #[cfg(unix)]
+            Listener::UnixFd(lst) => server.listen_uds(lst)?,
+        };
+    }
+    let server = server.run();
+    graceful_stop(server.clone(), readiness, Duration::from_secs(pre_stop));
+    let result = server.await;
+    #[cfg(feature = "tls")]
+    if let Some(redirect) = redirect {
+        redirect.stop(true).await;
+    }
+    hooks.run();
+    flush_log();
+    result
 }
@@ -0,0 +1,220 @@
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::io::FromRawFd;
+#[cfg(unix)]
+use std::os::unix::io::IntoRawFd;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+
+/// First file descriptor passed by systemd socket activation.
+#[cfg(unix)]
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Listener.
+///
+/// Parsed from `application.server.listeners`:
+/// - `host:port`, tcp address.
+/// - `unix:/path.sock`, unix domain socket.
+/// - `fd:N`, inherited tcp or unix listener.
+/// - `systemd`, all listeners passed by socket activation (`LISTEN_FDS`).
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(String),
+    TcpFd(TcpListener),
+    #[cfg(unix)]
+    Unix(String),
+    #[cfg(unix)]
+    UnixFd(UnixListener),
+}
+
+impl Listener {
+    /// Parse a listener entry, `systemd` may produce multiple listeners.
+    ///
+    /// # Safety
+    ///
+    /// `fd:N` takes ownership of file descriptor `N`, which must be handed to this process
+    /// as a listener and not be owned by anyone else.
+    pub unsafe fn parse(value: &str) -> Result<Vec<Listener>, Error> {
+        let value = value.trim();
+        #[cfg(unix)]
+        {
+            if let Some(path) = value.strip_prefix("unix:") {
+                return Ok(vec![Listener::Unix(path.to_owned())]);
+            }
+            if let Some(fd) = value.strip_prefix("fd:") {
+                let fd = fd.parse().map_err(|_| invalid(value))?;
+                return Ok(vec![Listener::from_raw_fd(fd)?]);
+            }
+            if value == "systemd" {
+                return systemd_listeners();
+            }
+        }
+        if value.is_empty() || value.ends_with(':') {
+            return Err(invalid(value));
+        }
+        Ok(vec![Listener::Tcp(value.to_owned())])
+    }
+
+    /// Take ownership of an inherited listener, tcp or unix.
+    ///
+    /// `fd` is left open and untouched if it is not a listening socket.
+    ///
+    /// # Safety
+    ///
+    /// A listening `fd` must not be owned by anyone else.
+    #[cfg(unix)]
+    pub unsafe fn from_raw_fd(fd: RawFd) -> Result<Listener, Error> {
+        if !is_listening(fd) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("File descriptor {} is not a listening socket", fd),
+            ));
+        }
+        let tcp = TcpListener::from_raw_fd(fd);
+        if tcp.local_addr().is_ok() {
+            return match tcp.set_nonblocking(true) {
+                Ok(()) => Ok(Listener::TcpFd(tcp)),
+                Err(e) => {
+                    // Give the fd back to its owner.
+                    let _ = tcp.into_raw_fd();
+                    Err(e)
+                }
+            };
+        }
+        let unix = UnixListener::from_raw_fd(tcp.into_raw_fd());
+        match unix.local_addr().and_then(|_| unix.set_nonblocking(true)) {
+            Ok(()) => Ok(Listener::UnixFd(unix)),
+            Err(e) => {
+                let _ = unix.into_raw_fd();
+                Err(e)
+            }
+        }
+    }
+
+    /// Tcp address, if known before binding.
+    pub fn addr(&self) -> Option<String> {
+        match self {
+            Listener::Tcp(addr) => Some(addr.clone()),
+            Listener::TcpFd(lst) => lst.local_addr().ok().map(|a| a.to_string()),
+            #[cfg(unix)]
+            _ => None,
+        }
+    }
+}
+
+impl Display for Listener {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Listener::Tcp(addr) => write!(f, "{}", addr),
+            Listener::TcpFd(lst) => match lst.local_addr() {
+                Ok(addr) => write!(f, "{} (inherited)", addr),
+                _ => write!(f, "tcp (inherited)"),
+            },
+            #[cfg(unix)]
+            Listener::Unix(path) => write!(f, "unix:{}", path),
+            #[cfg(unix)]
+            Listener::UnixFd(lst) => match lst
+                .local_addr()
+                .ok()
+                .and_then(|a| a.as_pathname().map(|p| p.to_string_lossy().into_owned()))
+            {
+                Some(path) => write!(f, "unix:{} (inherited)", path),
+                _ => write!(f, "unix (inherited)"),
+            },
+        }
+    }
+}
+
+/// Whether `fd` is a socket accepting connections, without taking ownership.
+#[cfg(unix)]
+fn is_listening(fd: RawFd) -> bool {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let r = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    r == 0 && value != 0
+}
+
+fn invalid(value: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("Invalid listener {}", value),
+    )
+}
+
+#[cfg(unix)]
+fn systemd_listeners() -> Result<Vec<Listener>, Error> {
+    if let Ok(pid) = std::env::var("LISTEN_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return Ok(vec![]);
+        }
+    }
+    let fds = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|v| v.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    (LISTEN_FDS_START..LISTEN_FDS_START + fds)
+        .map(|fd| unsafe { Listener::from_raw_fd(fd) })
+        .collect()
+}
+
+/// Parse all listener entries.
+///
+/// # Safety
+///
+/// See [`Listener::parse`], every `fd:N` entry takes ownership of `N`.
+pub unsafe fn parse_listeners(values: &[String]) -> Result<Vec<Listener>, Error> {
+    let mut listeners = vec![];
+    for v in values {
+        listeners.extend(Listener::parse(v)?);
+    }
+    if listeners.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "No listener configured",
+        ));
+    }
+    Ok(listeners)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::listener::*;
+
+    #[test]
+    fn test_parse() {
+        let v = unsafe { Listener::parse("127.0.0.1:8080") }.unwrap();
+        assert_eq!(Some("127.0.0.1:8080".to_owned()), v[0].addr());
+        assert!(unsafe { Listener::parse("") }.is_err());
+        assert!(unsafe { Listener::parse("127.0.0.1:") }.is_err());
+        #[cfg(unix)]
+        {
+            let v = unsafe { Listener::parse("unix:/tmp/fall.sock") }.unwrap();
+            assert_eq!("unix:/tmp/fall.sock", format!("{}", v[0]));
+            assert!(unsafe { Listener::parse("fd:x") }.is_err());
+            // Not a socket, stdout stays open.
+            assert!(unsafe { Listener::parse("fd:1") }.is_err());
+            assert_ne!(-1, unsafe { libc::fcntl(1, libc::F_GETFD) });
+            let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = tcp.local_addr().unwrap().to_string();
+            let v = unsafe { Listener::parse(&format!("fd:{}", tcp.into_raw_fd())) }.unwrap();
+            assert_eq!(Some(addr), v[0].addr());
+        }
+        assert!(unsafe { parse_listeners(&[]) }.is_err());
+    }
+}
@@ -1,5 +1,7 @@
 use crate::endpoints::CheckHealth;
 use crate::error::FallError;
+use crate::section::ConfigSection;
+use crate::section::ValidationErrors;
 use crate::PoolConfig;
 use fall_log::info;
 use r2d2::PooledConnection;
@@ -30,6 +32,19 @@ impl CheckHealth for RedisConn {
     }
 }
 
+impl ConfigSection for RedisConfig {
+    const KEY: &'static str = "redis";
+
+    fn validate(&self, errors: &mut ValidationErrors) {
+        if self.url.is_empty() {
+            errors.add("url", "must not be empty");
+        }
+        if let Some(pool) = &self.pool {
+            pool.validate(errors);
+        }
+    }
+}
+
 impl RedisConfig {
     pub fn init(&self) -> Result<RedisConn, FallError> {
         info!("Init Redis...");
@@ -0,0 +1,172 @@
+use crate::env::ConfigLoader;
+use crate::env::PropertySources;
+use crate::load_config;
+use actix_rt::time::interval;
+use config::Config;
+use fall_log::error;
+use fall_log::info;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::Duration;
+use std::time::SystemTime;
+
+/// Refresh listener.
+///
+/// Notified with the new config after it is published.
+pub trait RefreshListener {
+    fn on_refresh(&self, config: &Config);
+}
+
+impl<F: Fn(&Config)> RefreshListener for F {
+    fn on_refresh(&self, config: &Config) {
+        self(config)
+    }
+}
+
+#[derive(Default)]
+pub struct RefreshList(Vec<Box<dyn RefreshListener>>);
+
+impl RefreshList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_listener(&mut self, listener: Box<dyn RefreshListener>) {
+        self.0.push(listener);
+    }
+}
+
+struct Snapshot {
+    config: Arc<Config>,
+    sources: Arc<PropertySources>,
+}
+
+/// Shared config.
+///
+/// Latest config snapshot, replaced as a whole when config is reloaded.
+#[derive(Clone)]
+pub struct SharedConfig(Arc<RwLock<Snapshot>>);
+
+impl SharedConfig {
+    pub fn new(config: Config, sources: PropertySources) -> Self {
+        SharedConfig(Arc::new(RwLock::new(Snapshot {
+            config: Arc::new(config),
+            sources: Arc::new(sources),
+        })))
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.0.read().expect("Config lock failed").config.clone()
+    }
+
+    pub fn get_sources(&self) -> Arc<PropertySources> {
+        self.0.read().expect("Config lock failed").sources.clone()
+    }
+
+    fn set(&self, config: Config, sources: PropertySources) {
+        *self.0.write().expect("Config lock failed") = Snapshot {
+            config: Arc::new(config),
+            sources: Arc::new(sources),
+        };
+    }
+}
+
+fn fingerprint(files: Vec<PathBuf>) -> Vec<(PathBuf, Option<SystemTime>)> {
+    files
+        .into_iter()
+        .map(|f| {
+            let modified = f.metadata().and_then(|m| m.modified()).ok();
+            (f, modified)
+        })
+        .collect()
+}
+
+fn refresh(loader: &ConfigLoader, shared: &SharedConfig, listeners: &RefreshList) {
+    match load_config(loader) {
+        Ok((config, sources)) => {
+            info!("Config reloaded");
+            shared.set(config, sources);
+            let config = shared.get();
+            for l in listeners.0.iter() {
+                l.on_refresh(&config);
+            }
+        }
+        Err(e) => error!("Reload config failed: {}", e),
+    }
+}
+
+/// Reload config when config files change, polling every `application.config.refresh.interval`
+/// seconds (default 10, 0 to disable), or when receiving SIGHUP.
+pub(crate) fn watch(loader: ConfigLoader, shared: SharedConfig, listeners: RefreshList) {
+    let secs = shared
+        .get()
+        .get::<u64>("application.config.refresh.interval")
+        .unwrap_or(10);
+    let listeners = Rc::new(listeners);
+    if secs > 0 {
+        let (loader, shared, listeners) = (loader.clone(), shared.clone(), listeners.clone());
+        actix_rt::spawn(async move {
+            let mut last = fingerprint(loader.existing_files());
+            let mut tick = interval(Duration::from_secs(secs));
+            loop {
+                tick.tick().await;
+                let current = fingerprint(loader.existing_files());
+                if current != last {
+                    last = current;
+                    refresh(&loader, &shared, &listeners);
+                }
+            }
+        });
+    }
+    #[cfg(unix)]
+    actix_rt::spawn(async move {
+        use actix_rt::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::hangup()) {
+            Ok(mut s) => {
+                while s.recv().await.is_some() {
+                    refresh(&loader, &shared, &listeners);
+                }
+            }
+            Err(e) => error!("Listen SIGHUP failed: {}", e),
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use crate::reload::*;
+    use std::cell::RefCell;
+
+    #[test]
+    fn test_refresh() {
+        let dir = std::env::temp_dir().join(format!("fall-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("app.toml");
+        std::fs::write(&file, "hello = \"one\"").unwrap();
+        let loader =
+            ConfigLoader::from_args(vec![format!("--application.config.dir={}", dir.display())]);
+        let (config, sources) = load_config(&loader).unwrap();
+        let shared = SharedConfig::new(config, sources);
+        let seen = Rc::new(RefCell::new(vec![]));
+        let mut listeners = RefreshList::new();
+        let s = seen.clone();
+        listeners.add_listener(Box::new(move |c: &Config| {
+            s.borrow_mut().push(c.get_str("hello").unwrap())
+        }));
+        let hello = |shared: &SharedConfig| shared.get().get_str("hello").unwrap();
+
+        std::fs::write(&file, "hello = \"two\"").unwrap();
+        refresh(&loader, &shared, &listeners);
+        assert_eq!("two", hello(&shared));
+        assert_eq!(vec!["two"], *seen.borrow());
+
+        // A failed reload keeps the last snapshot and does not notify.
+        std::fs::write(&file, "hello = ").unwrap();
+        refresh(&loader, &shared, &listeners);
+        assert_eq!("two", hello(&shared));
+        assert_eq!(1, seen.borrow().len());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
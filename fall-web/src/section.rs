@@ -0,0 +1,170 @@
+use actix_web::web::ServiceConfig;
+use config::Config;
+use serde::de::DeserializeOwned;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::sync::Arc;
+
+/// Config section.
+///
+/// A typed struct deserialized from the config key `KEY`,
+/// validated once at startup and injected as `Data<T>` into handlers.
+pub trait ConfigSection: DeserializeOwned + Clone + 'static {
+    const KEY: &'static str;
+
+    /// Record every invalid field into `errors`.
+    fn validate(&self, _errors: &mut ValidationErrors) {}
+}
+
+/// Validation errors.
+///
+/// Invalid fields collected from all validated sections.
+#[derive(Debug, Default)]
+pub struct ValidationErrors {
+    prefix: String,
+    errors: Vec<(String, String)>,
+}
+
+impl ValidationErrors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, field: &str, message: &str) {
+        let field = if self.prefix.is_empty() {
+            field.to_owned()
+        } else {
+            format!("{}.{}", self.prefix, field)
+        };
+        self.errors.push((field, message.to_owned()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn errors(&self) -> &[(String, String)] {
+        &self.errors
+    }
+}
+
+impl Display for ValidationErrors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "Invalid config")?;
+        let mut flag = false;
+        for (k, v) in self.errors.iter() {
+            f.write_str(if flag { ", " } else { ": " })?;
+            flag = true;
+            write!(f, "{} {}", k, v)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+fn load_into<T: ConfigSection>(config: &Config, errors: &mut ValidationErrors) -> Option<T> {
+    errors.prefix = T::KEY.to_owned();
+    let section = match config.get::<T>(T::KEY) {
+        Ok(v) => v,
+        Err(e) => {
+            errors.prefix.clear();
+            errors.add(T::KEY, &format!("is invalid, {}", e));
+            return None;
+        }
+    };
+    let len = errors.errors.len();
+    section.validate(errors);
+    errors.prefix.clear();
+    if errors.errors.len() > len {
+        return None;
+    }
+    Some(section)
+}
+
+/// Deserialize and validate a single section.
+pub fn load_section<T: ConfigSection>(config: &Config) -> Result<T, ValidationErrors> {
+    let mut errors = ValidationErrors::new();
+    load_into(config, &mut errors).ok_or(errors)
+}
+
+pub(crate) type Binder = Box<dyn Fn(&mut ServiceConfig) + Send + Sync>;
+
+fn bind_section<T: ConfigSection + Send + Sync>(
+    config: &Config,
+    errors: &mut ValidationErrors,
+) -> Option<Binder> {
+    let section = load_into::<T>(config, errors)?;
+    Some(Box::new(move |cfg: &mut ServiceConfig| {
+        cfg.data(section.clone());
+    }))
+}
+
+/// Config sections registered at startup.
+#[derive(Default)]
+pub struct ConfigSections(Vec<fn(&Config, &mut ValidationErrors) -> Option<Binder>>);
+
+impl ConfigSections {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<T: ConfigSection + Send + Sync>(&mut self) {
+        self.0.push(bind_section::<T>);
+    }
+
+    /// Validate all sections, reporting every problem at once.
+    pub(crate) fn bind(&self, config: &Config) -> Result<Arc<Vec<Binder>>, ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+        let mut binders = vec![];
+        for f in self.0.iter() {
+            if let Some(b) = f(config, &mut errors) {
+                binders.push(b);
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Arc::new(binders))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::section::*;
+    use serde::Deserialize;
+
+    #[derive(Clone, Deserialize)]
+    struct Server {
+        port: u16,
+        name: String,
+    }
+
+    impl ConfigSection for Server {
+        const KEY: &'static str = "server";
+
+        fn validate(&self, errors: &mut ValidationErrors) {
+            if self.port < 1024 {
+                errors.add("port", "must not be less than 1024");
+            }
+            if self.name.is_empty() {
+                errors.add("name", "must not be empty");
+            }
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut config = Config::new();
+        config.set("server.port", 80).unwrap();
+        config.set("server.name", "").unwrap();
+        let e = load_section::<Server>(&config).err().unwrap();
+        assert_eq!(
+            "Invalid config: server.port must not be less than 1024, server.name must not be empty",
+            format!("{}", e)
+        );
+        config.set("server.port", 8080).unwrap();
+        config.set("server.name", "fall").unwrap();
+        assert!(load_section::<Server>(&config).is_ok());
+    }
+}
@@ -0,0 +1,116 @@
+use crate::endpoints::CheckHealth;
+use crate::error::FallError;
+use actix_rt::signal::ctrl_c;
+use actix_rt::time::delay_for;
+use actix_web::dev::Server;
+use actix_web::http::StatusCode;
+use fall_log::info;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Readiness.
+///
+/// Turns DOWN when the server starts shutting down, so load balancers stop routing to it.
+#[derive(Clone)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Readiness(Arc::new(AtomicBool::new(true)))
+    }
+}
+
+impl Readiness {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn set_ready(&self, ready: bool) {
+        self.0.store(ready, Ordering::SeqCst);
+    }
+}
+
+impl CheckHealth for Readiness {
+    fn check(&self) -> Result<(), FallError> {
+        if self.is_ready() {
+            return Ok(());
+        }
+        Err(FallError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Server is shutting down",
+        ))
+    }
+}
+
+/// Shutdown hooks.
+///
+/// Run in registration order after in-flight requests are drained.
+#[derive(Default)]
+pub struct ShutdownHooks(Vec<(String, Box<dyn FnOnce()>)>);
+
+impl ShutdownHooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_hook(&mut self, name: &str, hook: Box<dyn FnOnce()>) {
+        self.0.push((name.to_owned(), hook));
+    }
+
+    pub(crate) fn run(self) {
+        for (name, hook) in self.0 {
+            info!("Run shutdown hook {}", name);
+            hook();
+        }
+    }
+}
+
+async fn wait_signal() {
+    #[cfg(unix)]
+    {
+        use actix_rt::signal::unix::{signal, SignalKind};
+        use futures_util::future::select;
+        if let Ok(mut term) = signal(SignalKind::terminate()) {
+            select(Box::pin(term.recv()), Box::pin(ctrl_c())).await;
+            return;
+        }
+    }
+    let _ = ctrl_c().await;
+}
+
+/// On SIGTERM or SIGINT, mark readiness DOWN, wait `pre_stop`,
+/// then stop accepting connections and drain in-flight requests.
+pub(crate) fn graceful_stop(server: Server, readiness: Readiness, pre_stop: Duration) {
+    actix_rt::spawn(async move {
+        wait_signal().await;
+        info!("Shutting down, wait {:?} before stop", pre_stop);
+        readiness.set_ready(false);
+        delay_for(pre_stop).await;
+        server.stop(true).await;
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use crate::shutdown::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_shutdown_hooks() {
+        let ran = Rc::new(RefCell::new(vec![]));
+        let mut hooks = ShutdownHooks::new();
+        for name in &["redis", "database", "flush"] {
+            let ran = ran.clone();
+            hooks.add_hook(name, Box::new(move || ran.borrow_mut().push(*name)));
+        }
+        hooks.run();
+        assert_eq!(vec!["redis", "database", "flush"], *ran.borrow());
+    }
+}
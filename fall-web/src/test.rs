@@ -0,0 +1,720 @@
+use crate::configure_log;
+use crate::endpoints::CheckHealth;
+use crate::endpoints::HealthList;
+use crate::env::PropertySources;
+use crate::new_app;
+use crate::reload::RefreshList;
+use crate::section::ConfigSections;
+use crate::shutdown::ShutdownHooks;
+use crate::AppContext;
+use crate::Application;
+use crate::ConfigLoader;
+use crate::FallClient;
+use crate::FallServer;
+use actix_http::Request;
+use actix_service::Service;
+use actix_web::body::Body;
+use actix_web::dev::ServiceRequest;
+use actix_web::dev::ServiceResponse;
+use actix_web::web::ServiceConfig;
+use actix_web::App;
+use actix_web::Error;
+use config::Config;
+use fall_log::capture_panics;
+use fall_log::CaptureLog;
+use fall_log::DefaultGuard;
+use fall_log::FallLog;
+use fall_log::Regex;
+use std::io;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// Log capture.
+///
+/// Writer keeping every line written by `FallLog`.
+#[derive(Clone, Default)]
+pub struct LogCapture(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for LogCapture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().expect("Log lock failed").write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl LogCapture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        let buf = self.0.lock().expect("Log lock failed");
+        String::from_utf8_lossy(&buf)
+            .lines()
+            .map(|l| l.to_owned())
+            .collect()
+    }
+
+    pub fn contains(&self, text: &str) -> bool {
+        self.lines().iter().any(|l| l.contains(text))
+    }
+
+    /// Lines logged inside the span of `trace_id`.
+    pub fn lines_with_trace(&self, trace_id: &str) -> Vec<String> {
+        self.lines()
+            .into_iter()
+            .filter(|l| trace_of(l) == Some(trace_id))
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.0.lock().expect("Log lock failed").clear();
+    }
+}
+
+/// Trace id of a line formatted as `{ts} {LEVEL} [{app},{trace_id},...] ...`.
+fn trace_of(line: &str) -> Option<&str> {
+    let start = line.find(" [")? + 2;
+    let end = start + line[start..].find(']')?;
+    line[start..end].split(',').nth(1).filter(|t| !t.is_empty())
+}
+
+type CheckFactory = Arc<dyn Fn() -> Box<dyn CheckHealth> + Send + Sync>;
+
+/// Server under test, delegating every method to the wrapped server.
+#[derive(Clone)]
+struct TestFallServer<A> {
+    app: A,
+    checks: Vec<(String, CheckFactory)>,
+}
+
+impl<A: FallServer> FallServer for TestFallServer<A> {
+    type H = A::H;
+    type W = A::W;
+
+    fn get_addr(&self) -> String {
+        self.app.get_addr()
+    }
+
+    fn get_listeners(&self) -> Vec<String> {
+        self.app.get_listeners()
+    }
+
+    fn get_workers(&self) -> Option<usize> {
+        self.app.get_workers()
+    }
+
+    fn get_backlog(&self) -> Option<i32> {
+        self.app.get_backlog()
+    }
+
+    fn get_max_connections(&self) -> Option<usize> {
+        self.app.get_max_connections()
+    }
+
+    fn get_keep_alive(&self) -> Option<usize> {
+        self.app.get_keep_alive()
+    }
+
+    fn get_client_timeout(&self) -> Option<u64> {
+        self.app.get_client_timeout()
+    }
+
+    fn get_shutdown_timeout(&self) -> u64 {
+        self.app.get_shutdown_timeout()
+    }
+
+    fn get_pre_stop(&self) -> u64 {
+        self.app.get_pre_stop()
+    }
+
+    fn get_max_payload_size(&self) -> Option<usize> {
+        self.app.get_max_payload_size()
+    }
+
+    fn get_max_json_size(&self) -> Option<usize> {
+        self.app.get_max_json_size()
+    }
+
+    fn get_response_headers(&self) -> Vec<String> {
+        self.app.get_response_headers()
+    }
+
+    fn get_log_fields(&self) -> Vec<String> {
+        self.app.get_log_fields()
+    }
+
+    fn get_redact_fields(&self) -> Vec<String> {
+        self.app.get_redact_fields()
+    }
+
+    fn get_redact_patterns(&self) -> Result<Vec<Regex>, config::ConfigError> {
+        self.app.get_redact_patterns()
+    }
+
+    fn new_request_handler(&self) -> Self::H {
+        self.app.new_request_handler()
+    }
+
+    fn new_log(&self) -> FallLog<Self::W> {
+        self.app.new_log()
+    }
+
+    fn new_client(&self) -> FallClient {
+        self.app.new_client()
+    }
+
+    fn get_app(&self) -> &Application {
+        self.app.get_app()
+    }
+
+    fn get_config(&self) -> &Config {
+        self.app.get_config()
+    }
+
+    fn get_property_sources(&self) -> PropertySources {
+        self.app.get_property_sources()
+    }
+
+    fn config_mut(&mut self) -> Option<(&mut Config, &mut PropertySources)> {
+        self.app.config_mut()
+    }
+
+    fn get_config_loader(&self) -> Option<ConfigLoader> {
+        self.app.get_config_loader()
+    }
+
+    fn refresh_listeners(&self) -> RefreshList {
+        self.app.refresh_listeners()
+    }
+
+    fn health_check(&self) -> HealthList {
+        let mut list = self.app.health_check();
+        for (name, check) in self.checks.iter() {
+            list.add_check(name, check());
+        }
+        list
+    }
+
+    fn shutdown_hooks(&self) -> ShutdownHooks {
+        self.app.shutdown_hooks()
+    }
+
+    fn config_sections(&self) -> ConfigSections {
+        self.app.config_sections()
+    }
+
+    #[cfg(feature = "redis")]
+    fn get_redis(&self) -> Result<crate::redis::RedisConn, crate::FallError> {
+        self.app.get_redis()
+    }
+
+    #[cfg(feature = "database")]
+    fn get_database(&self) -> Result<crate::database::DatabaseConn, crate::FallError> {
+        self.app.get_database()
+    }
+
+    fn config<T, B>(&self, client: FallClient, app: App<T, B>) -> App<T, B>
+    where
+        B: crate::MessageBody,
+        T: crate::ServiceFactory<
+            Config = (),
+            Request = ServiceRequest,
+            Response = ServiceResponse<B>,
+            Error = Error,
+            InitError = (),
+        >,
+    {
+        self.app.config(client, app)
+    }
+}
+
+/// Test app.
+///
+/// Builds the same app as `start` for `actix_web::test`.
+/// Redis and database pools are only initialized when their section is configured.
+///
+/// ```ignore
+/// let mut srv = TestApp::new(DefaultFallServer::default())
+///     .set("application.name", "demo")
+///     .add_check("redis", FakeRedis)
+///     .init_service(config)
+///     .await;
+/// let res = srv.call(TestRequest::get().uri("/hello").to_request()).await;
+/// assert!(!srv.log().lines_with_trace("0000000000000001").is_empty());
+/// ```
+pub struct TestApp<A> {
+    server: TestFallServer<A>,
+    log: LogCapture,
+    capture: CaptureLog,
+}
+
+impl<A: FallServer + 'static> TestApp<A> {
+    pub fn new(app: A) -> Self {
+        TestApp {
+            server: TestFallServer {
+                app,
+                checks: vec![],
+            },
+            log: LogCapture::new(),
+            capture: CaptureLog::new(),
+        }
+    }
+
+    /// Override a config key, the wrapped server must implement `FallServer::config_mut`.
+    pub fn set(mut self, key: &str, value: &str) -> Self {
+        let (config, sources) = self
+            .server
+            .config_mut()
+            .expect("Server does not support config overrides");
+        sources
+            .set("test", config, key, value)
+            .expect("Set config failed");
+        self
+    }
+
+    /// Add a health check, replacing the one with the same name.
+    pub fn add_check<C>(mut self, name: &str, check: C) -> Self
+    where
+        C: CheckHealth + Clone + Send + Sync + 'static,
+    {
+        let factory: CheckFactory = Arc::new(move || Box::new(check.clone()));
+        self.server.checks.push((name.to_owned(), factory));
+        self
+    }
+
+    pub fn get_config(&self) -> &Config {
+        self.server.get_config()
+    }
+
+    /// Initialize the app, logging into a `LogCapture` for the current thread
+    /// with the settings of the wrapped server's `new_log`.
+    pub async fn init_service<F>(
+        self,
+        config: F,
+    ) -> TestService<impl Service<Request = Request, Response = ServiceResponse<Body>, Error = Error>>
+    where
+        F: FnOnce(&mut ServiceConfig),
+    {
+        let log = self
+            .server
+            .new_log()
+            .with_writer(self.log.clone())
+            .capture(self.capture.clone());
+        let guard = configure_log(&self.server, log).set_default();
+        capture_panics();
+        let ctx = match AppContext::new(&self.server, true) {
+            Ok(ctx) => ctx,
+            Err(e) => panic!("Init test app failed: {}", e),
+        };
+        let service = actix_web::test::init_service(new_app(&self.server, &ctx, config)).await;
+        TestService {
+            service,
+            log: self.log,
+            capture: self.capture,
+            _guard: guard,
+        }
+    }
+}
+
+/// Test service.
+///
+/// Initialized app with its captured log.
+pub struct TestService<S> {
+    service: S,
+    log: LogCapture,
+    capture: CaptureLog,
+    _guard: DefaultGuard,
+}
+
+impl<S> TestService<S>
+where
+    S: Service<Request = Request, Response = ServiceResponse<Body>, Error = Error>,
+{
+    pub async fn call(&mut self, req: Request) -> ServiceResponse<Body> {
+        actix_web::test::call_service(&mut self.service, req).await
+    }
+
+    pub fn service(&mut self) -> &mut S {
+        &mut self.service
+    }
+
+    pub fn log(&self) -> &LogCapture {
+        &self.log
+    }
+
+    /// Structured records, see `RecordQuery`.
+    pub fn records(&self) -> &CaptureLog {
+        &self.capture
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shutdown::Readiness;
+    use crate::test::*;
+    use crate::DefaultFallServer;
+    use crate::FallError;
+    use actix_web::test::read_body;
+    use actix_web::test::TestRequest;
+    use actix_web::web::resource;
+    use actix_web::HttpResponse;
+    use fall_log::info;
+    use fall_log::RecordQuery;
+
+    #[derive(Clone)]
+    struct Down;
+
+    impl CheckHealth for Down {
+        fn check(&self) -> Result<(), FallError> {
+            Err(FallError::bad_request("down"))
+        }
+    }
+
+    async fn stop(ready: actix_web::web::Data<Readiness>) -> HttpResponse {
+        ready.set_ready(false);
+        HttpResponse::Ok().finish()
+    }
+
+    async fn hello(config: actix_web::web::Data<Config>) -> HttpResponse {
+        info!("Hello {}", config.get_str("hello.name").unwrap_or_default());
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_rt::test]
+    async fn test_app() {
+        let app = DefaultFallServer::new(Config::new(), Application::default());
+        let mut srv = TestApp::new(app)
+            .set("hello.name", "fall")
+            .add_check("fake", Down)
+            .init_service(|cfg| {
+                cfg.service(resource("/hello").to(hello))
+                    .service(resource("/stop").to(stop));
+            })
+            .await;
+
+        let req = TestRequest::get()
+            .uri("/hello")
+            .header("X-B3-TraceId", "1")
+            .header("X-Request-Id", "req-1")
+            .to_request();
+        let res = srv.call(req).await;
+        assert!(res.status().is_success());
+        let header = |name| res.headers().get(name).unwrap().to_str().unwrap();
+        assert_eq!("req-1", header("X-Request-Id"));
+        assert_eq!("0000000000000001", header("X-B3-TraceId"));
+        assert_eq!(
+            "00-00000000000000000000000000000001-0000000000000001-01",
+            header("traceresponse")
+        );
+        let lines = srv.log().lines_with_trace("0000000000000001");
+        assert_eq!(1, lines.len());
+        assert!(lines[0].ends_with("Hello fall"));
+        srv.records().assert_logged(
+            &RecordQuery::new()
+                .trace_id("0000000000000001")
+                .field_eq("request_id", "req-1")
+                .message("Hello fall"),
+        );
+
+        let req = TestRequest::get().uri("/endpoints/health").to_request();
+        let body = read_body(srv.call(req).await).await;
+        assert!(String::from_utf8_lossy(&body).contains("\"fake\":{\"status\":\"DOWN\""));
+
+        let readiness = || {
+            TestRequest::get()
+                .uri("/endpoints/health/readiness")
+                .to_request()
+        };
+        assert!(srv.call(readiness()).await.status().is_success());
+        srv.call(TestRequest::get().uri("/stop").to_request()).await;
+        let res = srv.call(readiness()).await;
+        assert_eq!(503, res.status().as_u16());
+        let body = read_body(res).await;
+        assert!(String::from_utf8_lossy(&body).contains("\"status\":\"DOWN\""));
+    }
+
+    async fn order() -> Result<HttpResponse, FallError> {
+        Err(FallError::bad_request("Invalid order")
+            .with_code("ORDER_INVALID")
+            .with_field_error("amount", "must be positive")
+            .with_extension("balance", 30))
+    }
+
+    async fn fail() -> Result<HttpResponse, FallError> {
+        let e = std::io::Error::other("db.internal:5432 refused");
+        Err(FallError::internal("Load order failed").with_source(e))
+    }
+
+    #[actix_rt::test]
+    async fn test_problem() {
+        let app = DefaultFallServer::new(Config::new(), Application::default());
+        let mut srv = TestApp::new(app)
+            .set("application.error.problem_json", "true")
+            .init_service(|cfg| {
+                cfg.service(resource("/order").to(order))
+                    .service(resource("/fail").to(fail));
+            })
+            .await;
+        let req = TestRequest::get()
+            .uri("/order")
+            .header("X-B3-TraceId", "2")
+            .to_request();
+        let res = srv.call(req).await;
+        assert_eq!(400, res.status().as_u16());
+        assert_eq!(
+            "application/problem+json",
+            res.headers().get("content-type").unwrap()
+        );
+        let body: serde_json::Value = actix_web::test::read_body_json(res).await;
+        assert_eq!(
+            serde_json::json!({
+                "type": "urn:problem-type:ORDER_INVALID",
+                "title": "Bad Request",
+                "status": 400,
+                "detail": "Invalid order",
+                "instance": "/order",
+                "trace_id": "0000000000000002",
+                "code": "ORDER_INVALID",
+                "errors": [{"field": "amount", "message": "must be positive"}],
+                "balance": 30
+            }),
+            body
+        );
+
+        let req = TestRequest::get()
+            .uri("/fail")
+            .header("X-B3-TraceId", "3")
+            .to_request();
+        let body: serde_json::Value = actix_web::test::read_body_json(srv.call(req).await).await;
+        assert_eq!("Internal Server Error", body["detail"]);
+        assert_eq!("0000000000000003", body["trace_id"]);
+        srv.records().assert_logged(
+            &RecordQuery::new()
+                .level(fall_log::Level::ERROR)
+                .trace_id("0000000000000003")
+                .message("Load order failed, caused by: db.internal:5432 refused"),
+        );
+
+        // Settings are per app.
+        let app = DefaultFallServer::new(Config::new(), Application::default());
+        let mut plain = TestApp::new(app)
+            .init_service(|cfg| {
+                cfg.service(resource("/order").to(order));
+            })
+            .await;
+        let req = TestRequest::get().uri("/order").to_request();
+        let res = plain.call(req).await;
+        assert_eq!(
+            "application/json",
+            res.headers().get("content-type").unwrap()
+        );
+        let req = TestRequest::get().uri("/order").to_request();
+        let res = srv.call(req).await;
+        assert_eq!(
+            "application/problem+json",
+            res.headers().get("content-type").unwrap()
+        );
+    }
+
+    async fn slow() -> Result<HttpResponse, FallError> {
+        actix_rt::time::delay_for(std::time::Duration::from_millis(50)).await;
+        Err(FallError::bad_request("Slow failed"))
+    }
+
+    #[actix_rt::test]
+    async fn test_overlapping_errors() {
+        let app = DefaultFallServer::new(Config::new(), Application::default());
+        let mut srv = TestApp::new(app)
+            .set("application.error.problem_json", "true")
+            .set("application.log.span_close", "true")
+            .init_service(|cfg| {
+                cfg.service(resource("/slow").to(slow))
+                    .service(resource("/fast").to(hello));
+            })
+            .await;
+        let slow = srv.service().call(
+            TestRequest::get()
+                .uri("/slow")
+                .header("X-B3-TraceId", "a")
+                .to_request(),
+        );
+        let fast = srv.service().call(
+            TestRequest::get()
+                .uri("/fast")
+                .header("X-B3-TraceId", "b")
+                .to_request(),
+        );
+        let (slow, fast) = futures_util::future::join(slow, fast).await;
+        assert!(fast.unwrap().status().is_success());
+        let body: serde_json::Value = actix_web::test::read_body_json(slow.unwrap()).await;
+        assert_eq!("000000000000000a", body["trace_id"]);
+        assert_eq!("/slow", body["instance"]);
+        // The wait of the slow request is idle time of its own span.
+        let lines = srv.log().lines_with_trace("000000000000000a");
+        let close = lines.iter().find(|l| l.contains("close request")).unwrap();
+        let time = |name: &str| {
+            let v = close.split(name).nth(1).unwrap();
+            let v = v.split(',').next().unwrap();
+            let n = v.trim_end_matches(|c: char| c.is_alphabetic());
+            let unit = &v[n.len()..];
+            let n: f64 = n.parse().unwrap();
+            match unit {
+                "s" => n * 1000.0,
+                "ms" => n,
+                _ => 0.0,
+            }
+        };
+        assert!(time("idle=") >= 40.0);
+        assert!(time("busy=") < time("idle="));
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Order {
+        amount: u32,
+    }
+
+    async fn create(order: actix_web::web::Json<Order>) -> HttpResponse {
+        HttpResponse::Ok().body(format!("{}", order.amount))
+    }
+
+    #[actix_rt::test]
+    async fn test_framework_errors() {
+        let app = DefaultFallServer::new(Config::new(), Application::default());
+        let mut srv = TestApp::new(app)
+            .init_service(|cfg| {
+                cfg.service(resource("/orders").route(actix_web::web::post().to(create)));
+            })
+            .await;
+        let req = TestRequest::post()
+            .uri("/orders")
+            .header("content-type", "application/json")
+            .set_payload("{\"amount\":-1}")
+            .to_request();
+        let res = srv.call(req).await;
+        assert_eq!(400, res.status().as_u16());
+        let body: serde_json::Value = actix_web::test::read_body_json(res).await;
+        assert_eq!("INVALID_JSON", body["code"]);
+
+        let req = TestRequest::get()
+            .uri("/orders")
+            .header("X-B3-TraceId", "4")
+            .to_request();
+        let res = srv.call(req).await;
+        assert_eq!(405, res.status().as_u16());
+        let body: serde_json::Value = actix_web::test::read_body_json(res).await;
+        assert_eq!(405, body["status"]);
+        assert_eq!("0000000000000004", body["trace_id"]);
+    }
+
+    async fn login() -> HttpResponse {
+        HttpResponse::Unauthorized()
+            .header("WWW-Authenticate", "Bearer")
+            .header("Retry-After", "30")
+            .body("login please")
+    }
+
+    async fn gone() -> HttpResponse {
+        HttpResponse::NotFound().header("X-Reason", "gone").finish()
+    }
+
+    #[actix_rt::test]
+    async fn test_handler_errors() {
+        let app = DefaultFallServer::new(Config::new(), Application::default());
+        let mut srv = TestApp::new(app)
+            .init_service(|cfg| {
+                cfg.service(resource("/login").to(login))
+                    .service(resource("/gone").to(gone));
+            })
+            .await;
+        let req = TestRequest::get().uri("/login").to_request();
+        let res = srv.call(req).await;
+        assert_eq!(401, res.status().as_u16());
+        assert_eq!("Bearer", res.headers().get("WWW-Authenticate").unwrap());
+        assert_eq!("30", res.headers().get("Retry-After").unwrap());
+        assert_eq!("login please", read_body(res).await);
+
+        let req = TestRequest::get()
+            .uri("/gone")
+            .header("X-B3-TraceId", "7")
+            .to_request();
+        let res = srv.call(req).await;
+        assert_eq!(404, res.status().as_u16());
+        assert_eq!("gone", res.headers().get("X-Reason").unwrap());
+        let body: serde_json::Value = actix_web::test::read_body_json(res).await;
+        assert_eq!("0000000000000007", body["trace_id"]);
+    }
+
+    async fn boom() -> HttpResponse {
+        panic!("boom")
+    }
+
+    #[actix_rt::test]
+    async fn test_panic() {
+        let app = DefaultFallServer::new(Config::new(), Application::default());
+        let mut srv = TestApp::new(app)
+            .init_service(|cfg| {
+                cfg.service(resource("/boom").to(boom));
+            })
+            .await;
+        let req = TestRequest::get()
+            .uri("/boom")
+            .header("X-B3-TraceId", "5")
+            .to_request();
+        let err = srv.service().call(req).await.err().unwrap();
+        let res = err.as_response_error().error_response();
+        assert_eq!(500, res.status().as_u16());
+        assert_eq!(
+            "0000000000000005",
+            res.headers().get("X-B3-TraceId").unwrap()
+        );
+        let body = match res.body().as_ref() {
+            Some(actix_web::body::Body::Bytes(b)) => serde_json::from_slice(b).unwrap(),
+            _ => serde_json::Value::Null,
+        };
+        assert_eq!("0000000000000005", body["trace_id"]);
+        srv.records().assert_logged(
+            &RecordQuery::new()
+                .level(fall_log::Level::ERROR)
+                .trace_id("0000000000000005")
+                .message(": boom"),
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_log_fields() {
+        let app = DefaultFallServer::new(Config::new(), Application::default());
+        let mut srv = TestApp::new(app)
+            .set("hello.name", "fields")
+            .set(
+                "application.log.fields",
+                "tenant=header:X-Tenant-Id, method, padding=path, bad",
+            )
+            .set("application.log.span_close", "true")
+            .init_service(|cfg| {
+                cfg.service(resource("/hello").to(hello));
+            })
+            .await;
+        let req = TestRequest::get()
+            .uri("/hello")
+            .header("X-B3-TraceId", "6")
+            .header("X-Tenant-Id", "t1")
+            .to_request();
+        assert!(srv.call(req).await.status().is_success());
+        srv.records().assert_logged(
+            &RecordQuery::new()
+                .trace_id("0000000000000006")
+                .field_eq("padding", "/hello")
+                .field_eq("tenant", "t1")
+                .field_eq("method", "GET"),
+        );
+        // Span close is printed once enabled by config.
+        let lines = srv.log().lines_with_trace("0000000000000006");
+        assert!(lines
+            .iter()
+            .any(|l| l.contains("close ") && l.contains("busy=")));
+    }
+}
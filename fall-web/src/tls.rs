@@ -0,0 +1,393 @@
+use crate::section::load_section;
+use crate::section::ConfigSection;
+use crate::section::ValidationErrors;
+use actix_tls::rustls::TlsStream;
+use actix_web::dev::Server;
+use actix_web::http::header;
+use actix_web::rt::net::TcpStream;
+use actix_web::web::route;
+use actix_web::web::Data;
+use actix_web::App;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use actix_web::HttpServer;
+use config::Config;
+use rustls::internal::pemfile::certs;
+use rustls::internal::pemfile::pkcs8_private_keys;
+use rustls::internal::pemfile::rsa_private_keys;
+use rustls::AllowAnyAnonymousOrAuthenticatedClient;
+use rustls::AllowAnyAuthenticatedClient;
+use rustls::NoClientAuth;
+use rustls::RootCertStore;
+use rustls::ServerConfig;
+use rustls::Session;
+use serde::Deserialize;
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// Tls config, `application.tls`.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct TlsConfig {
+    cert: String,
+    key: String,
+    client_ca: Option<String>,
+    #[serde(default)]
+    client_auth_optional: bool,
+    redirect_port: Option<u16>,
+}
+
+impl ConfigSection for TlsConfig {
+    const KEY: &'static str = "application.tls";
+
+    fn validate(&self, errors: &mut ValidationErrors) {
+        if !Path::new(&self.cert).is_file() {
+            errors.add("cert", "file not found");
+        }
+        if !Path::new(&self.key).is_file() {
+            errors.add("key", "file not found");
+        }
+        if let Some(ca) = &self.client_ca {
+            if !Path::new(ca).is_file() {
+                errors.add("client_ca", "file not found");
+            }
+        }
+    }
+}
+
+fn open(path: &str) -> Result<BufReader<File>, Error> {
+    Ok(BufReader::new(File::open(path)?))
+}
+
+fn invalid(path: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Invalid pem file {}", path))
+}
+
+impl TlsConfig {
+    /// Load tls config if `application.tls.cert` is set.
+    pub(crate) fn load(config: &Config) -> Result<Option<Self>, ValidationErrors> {
+        if config.get::<String>("application.tls.cert").is_err() {
+            return Ok(None);
+        }
+        load_section(config).map(Some)
+    }
+
+    pub(crate) fn redirect_port(&self) -> Option<u16> {
+        self.redirect_port
+    }
+
+    pub(crate) fn server_config(&self) -> Result<ServerConfig, Error> {
+        let mut config = match &self.client_ca {
+            Some(ca) => {
+                let mut store = RootCertStore::empty();
+                store
+                    .add_pem_file(&mut open(ca)?)
+                    .map_err(|_| invalid(ca))?;
+                if self.client_auth_optional {
+                    ServerConfig::new(AllowAnyAnonymousOrAuthenticatedClient::new(store))
+                } else {
+                    ServerConfig::new(AllowAnyAuthenticatedClient::new(store))
+                }
+            }
+            _ => ServerConfig::new(NoClientAuth::new()),
+        };
+        let chain = certs(&mut open(&self.cert)?).map_err(|_| invalid(&self.cert))?;
+        let mut keys = pkcs8_private_keys(&mut open(&self.key)?).map_err(|_| invalid(&self.key))?;
+        if keys.is_empty() {
+            keys = rsa_private_keys(&mut open(&self.key)?).map_err(|_| invalid(&self.key))?;
+        }
+        if keys.is_empty() {
+            return Err(invalid(&self.key));
+        }
+        config
+            .set_single_cert(chain, keys.remove(0))
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        Ok(config)
+    }
+}
+
+/// Client certificate.
+///
+/// Certificate chain verified during the mutual tls handshake.
+#[derive(Clone, Debug)]
+pub struct ClientCertificate {
+    chain: Vec<Vec<u8>>,
+}
+
+impl ClientCertificate {
+    /// DER of the client certificate.
+    pub fn der(&self) -> &[u8] {
+        &self.chain[0]
+    }
+
+    pub fn chain(&self) -> &[Vec<u8>] {
+        &self.chain
+    }
+
+    /// Common name of the certificate subject.
+    pub fn common_name(&self) -> Option<String> {
+        common_name(self.der())
+    }
+}
+
+/// Read a DER element, returns tag, content and remaining bytes.
+fn read_tlv(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.first()?;
+    let first = *data.get(1)? as usize;
+    let (len, head) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let n = first & 0x7f;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let mut len = 0usize;
+        for i in 0..n {
+            len = (len << 8) | *data.get(2 + i)? as usize;
+        }
+        (len, 2 + n)
+    };
+    let end = head.checked_add(len)?;
+    if end > data.len() {
+        return None;
+    }
+    Some((tag, &data[head..end], &data[end..]))
+}
+
+fn common_name(der: &[u8]) -> Option<String> {
+    let (_, cert, _) = read_tlv(der)?;
+    let (_, tbs, _) = read_tlv(cert)?;
+    // Skip optional version, then serial, signature, issuer and validity.
+    let (tag, _, next) = read_tlv(tbs)?;
+    let mut rest = if tag == 0xa0 { next } else { tbs };
+    for _ in 0..4 {
+        rest = read_tlv(rest)?.2;
+    }
+    let (_, mut subject, _) = read_tlv(rest)?;
+    while !subject.is_empty() {
+        let (_, set, next) = read_tlv(subject)?;
+        subject = next;
+        let (_, attr, _) = read_tlv(set)?;
+        let (_, oid, value) = read_tlv(attr)?;
+        if oid == [0x55, 0x04, 0x03] {
+            let (_, v, _) = read_tlv(value)?;
+            return String::from_utf8(v.to_vec()).ok();
+        }
+    }
+    None
+}
+
+/// Key of a connection, peer address and local port.
+type ConnKey = (SocketAddr, u16);
+
+/// Client certificates.
+///
+/// Certificates of open connections by peer address and local port. Connection data of actix
+/// only reaches the first request or http/2 stream, so every request looks it up here instead.
+#[derive(Clone, Default)]
+pub(crate) struct ClientCertificates(Arc<Mutex<Registry>>);
+
+#[derive(Default)]
+struct Registry {
+    conns: HashMap<ConnKey, (ClientCertificate, Conn)>,
+    prune_at: usize,
+}
+
+/// Entries checked for closed connections once the registry grows past this size.
+const PRUNE_SIZE: usize = 1024;
+
+impl ClientCertificates {
+    /// Record the verified client certificate of a new tls connection.
+    pub(crate) fn on_connect(&self, conn: &dyn Any) {
+        if let Some(tls) = conn.downcast_ref::<TlsStream<TcpStream>>() {
+            let (tcp, session) = tls.get_ref();
+            if let (Ok(peer), Ok(local)) = (tcp.peer_addr(), tcp.local_addr()) {
+                let chain = session.get_peer_certificates().unwrap_or_default();
+                let cert = match chain.is_empty() {
+                    true => None,
+                    _ => Some(ClientCertificate {
+                        chain: chain.into_iter().map(|c| c.0).collect(),
+                    }),
+                };
+                self.set((peer, local.port()), cert, Conn::of(tcp));
+            }
+        }
+    }
+
+    /// A connection reusing the address of a closed one replaces its certificate.
+    fn set(&self, key: ConnKey, cert: Option<ClientCertificate>, conn: Conn) {
+        let mut registry = self.0.lock().expect("Certificates lock failed");
+        match cert {
+            Some(cert) => registry.conns.insert(key, (cert, conn)),
+            _ => registry.conns.remove(&key),
+        };
+        if registry.conns.len() > registry.prune_at.max(PRUNE_SIZE) {
+            registry.conns.retain(|k, (_, c)| c.is_open(k));
+            registry.prune_at = registry.conns.len() * 2;
+        }
+    }
+
+    pub(crate) fn get(&self, peer: SocketAddr, local: SocketAddr) -> Option<ClientCertificate> {
+        let registry = self.0.lock().expect("Certificates lock failed");
+        registry
+            .conns
+            .get(&(peer, local.port()))
+            .map(|(cert, _)| cert.clone())
+    }
+}
+
+#[cfg(unix)]
+type GetName =
+    unsafe extern "C" fn(libc::c_int, *mut libc::sockaddr, *mut libc::socklen_t) -> libc::c_int;
+
+/// Address of the socket `fd` by `getpeername` or `getsockname`, `None` once it is closed.
+#[cfg(unix)]
+fn sock_addr(fd: libc::c_int, get_name: GetName) -> Option<SocketAddr> {
+    use std::net::Ipv4Addr;
+    use std::net::Ipv6Addr;
+    use std::net::SocketAddrV6;
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let ptr = &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr;
+    if unsafe { get_name(fd, ptr, &mut len) } != 0 {
+        return None;
+    }
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let a = unsafe { *(ptr as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(a.sin_addr.s_addr));
+            Some(SocketAddr::new(ip.into(), u16::from_be(a.sin_port)))
+        }
+        libc::AF_INET6 => {
+            let a = unsafe { *(ptr as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(a.sin6_addr.s6_addr);
+            let port = u16::from_be(a.sin6_port);
+            Some(SocketAddrV6::new(ip, port, a.sin6_flowinfo, a.sin6_scope_id).into())
+        }
+        _ => None,
+    }
+}
+
+/// Socket of a connection, to find closed ones.
+struct Conn(#[cfg(unix)] std::os::unix::io::RawFd);
+
+impl Conn {
+    #[cfg(unix)]
+    fn of(tcp: &TcpStream) -> Self {
+        use std::os::unix::io::AsRawFd;
+        Conn(tcp.as_raw_fd())
+    }
+
+    #[cfg(not(unix))]
+    fn of(_: &TcpStream) -> Self {
+        Conn()
+    }
+
+    /// Whether the socket is still connected to `key`, the fd may be reused by another one.
+    #[cfg(unix)]
+    fn is_open(&self, key: &ConnKey) -> bool {
+        sock_addr(self.0, libc::getpeername) == Some(key.0)
+            && sock_addr(self.0, libc::getsockname).map(|a| a.port()) == Some(key.1)
+    }
+
+    /// Connections are never pruned without unix sockets.
+    #[cfg(not(unix))]
+    fn is_open(&self, _: &ConnKey) -> bool {
+        true
+    }
+}
+
+async fn redirect(req: HttpRequest, port: Data<u16>) -> HttpResponse {
+    let info = req.connection_info();
+    let host = info.host().split(':').next().unwrap_or("");
+    let port = match *port.as_ref() {
+        443 => String::new(),
+        p => format!(":{}", p),
+    };
+    HttpResponse::PermanentRedirect()
+        .header(
+            header::LOCATION,
+            format!("https://{}{}{}", host, port, req.uri()),
+        )
+        .finish()
+}
+
+/// Plain http server redirecting every request to the https port.
+pub(crate) fn redirect_server(addr: String, https_port: u16) -> Result<Server, Error> {
+    Ok(HttpServer::new(move || {
+        App::new()
+            .data(https_port)
+            .default_service(route().to(redirect))
+    })
+    .workers(1)
+    .disable_signals()
+    .bind(addr)?
+    .run())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::tls::*;
+
+    const CERT: &str = "-----BEGIN CERTIFICATE-----
+MIIBtjCCAVugAwIBAgIUV3jLbSmSCM4FlXVys4SX+BQfHwowCgYIKoZIzj0EAwIw
+LzELMAkGA1UEBhMCQ04xDTALBgNVBAoMBEZhbGwxETAPBgNVBAMMCGNsaWVudC0x
+MCAXDTI2MTAxODE3NDM0NVoYDzIxMjYwOTI0MTc0MzQ1WjAvMQswCQYDVQQGEwJD
+TjENMAsGA1UECgwERmFsbDERMA8GA1UEAwwIY2xpZW50LTEwWTATBgcqhkjOPQIB
+BggqhkjOPQMBBwNCAAQG/uJMO4/1q2URFIwipuycq4Nr03FP5yO83L9CrrxkVPeY
+5nx3bUgzD5DIz69slxysqPKKFZjSRYO4gxW3FeT7o1MwUTAdBgNVHQ4EFgQUYS4f
+wGTC5gfhSYa1JozsprEQBL4wHwYDVR0jBBgwFoAUYS4fwGTC5gfhSYa1JozsprEQ
+BL4wDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNJADBGAiEAsXjSHpljfL/i
+OcPOaoHPkwxpMVZdgCFLrorvro5+lZ8CIQDfg0pxIlF/WwRlGXFVTtBPvh/ABrlI
+THAE4Dde14KoaA==
+-----END CERTIFICATE-----
+";
+
+    fn der() -> Vec<u8> {
+        certs(&mut CERT.as_bytes()).unwrap().remove(0).0
+    }
+
+    #[test]
+    fn test_common_name() {
+        let cert = ClientCertificate { chain: vec![der()] };
+        assert_eq!(Some("client-1".to_owned()), cert.common_name());
+        let der = der();
+        for n in 0..der.len() {
+            assert_eq!(None, common_name(&der[..n]));
+        }
+        assert_eq!(None, read_tlv(&[0x30]));
+        assert_eq!(None, read_tlv(&[0x30, 0x03, 0x01]));
+        assert_eq!(None, read_tlv(&[0x30, 0x82, 0x01]));
+        assert_eq!(None, read_tlv(&[0x30, 0x80]));
+        assert_eq!(
+            Some((0x04, &[0x01u8][..], &[0x05u8][..])),
+            read_tlv(&[0x04, 0x01, 0x01, 0x05])
+        );
+    }
+
+    #[test]
+    fn test_client_certificates() {
+        let certs = ClientCertificates::default();
+        let peer: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let local: SocketAddr = "127.0.0.1:8443".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let cert = ClientCertificate { chain: vec![der()] };
+        let conn = || {
+            Conn(
+                #[cfg(unix)]
+                -1,
+            )
+        };
+        certs.set((peer, local.port()), Some(cert), conn());
+        let found = certs.get(peer, local).map(|c| c.common_name());
+        assert_eq!(Some(Some("client-1".to_owned())), found);
+        assert!(certs.get(peer, other).is_none());
+        certs.set((peer, local.port()), None, conn());
+        assert!(certs.get(peer, local).is_none());
+    }
+}
@@ -1,28 +1,174 @@
+use crate::error::ErrorContext;
+use crate::ErrorConfig;
+use crate::FallError;
 use crate::RequestHandler;
 use actix_service::Service;
 use actix_service::Transform;
 use actix_web::body::MessageBody;
 use actix_web::dev::ServiceRequest;
 use actix_web::dev::ServiceResponse;
+use actix_web::http::header::CONTENT_LENGTH;
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::http::HeaderMap;
+use actix_web::http::HeaderName;
+use actix_web::http::HeaderValue;
+use actix_web::web::Data;
 use actix_web::Error;
 use fall_log::*;
 use futures_core::future::LocalBoxFuture;
 use futures_util::future;
 use futures_util::future::FutureExt;
+use std::any::Any;
 use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
 use std::rc::Rc;
 use std::task::Context;
 use std::task::Poll;
 
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        return s;
+    }
+    match payload.downcast_ref::<String>() {
+        Some(s) => s,
+        _ => "Box<dyn Any>",
+    }
+}
+
 pub struct DefaultRequestHandler;
 
 impl RequestHandler for DefaultRequestHandler {}
 
+/// Trace header added to every response.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TraceHeader {
+    RequestId,
+    TraceId,
+    TraceResponse,
+}
+
+impl TraceHeader {
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "x-request-id" => Some(TraceHeader::RequestId),
+            "x-b3-traceid" => Some(TraceHeader::TraceId),
+            "traceresponse" => Some(TraceHeader::TraceResponse),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> HeaderName {
+        HeaderName::from_static(match self {
+            TraceHeader::RequestId => "x-request-id",
+            TraceHeader::TraceId => "x-b3-traceid",
+            TraceHeader::TraceResponse => "traceresponse",
+        })
+    }
+
+    fn value(self, trace: &OpenTrace, request_id: Option<&str>) -> String {
+        match self {
+            TraceHeader::RequestId => request_id.unwrap_or(&trace.trace_id).to_owned(),
+            TraceHeader::TraceId => trace.trace_id.clone(),
+            TraceHeader::TraceResponse => {
+                format!("00-{:0>32}-{:0>16}-01", trace.trace_id, trace.span_id)
+            }
+        }
+    }
+}
+
+/// Request value copied into a span field.
+#[derive(Clone, Debug, PartialEq)]
+enum FieldSource {
+    Header(HeaderName),
+    Method,
+    Path,
+    ClientIp,
+}
+
+/// Log field.
+///
+/// Parsed from `name=source`, source is `header:<Name>`, `method`, `path` or `client_ip`.
+/// A bare source is named after itself, like `method`.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct LogField {
+    name: String,
+    source: FieldSource,
+}
+
+impl LogField {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        let (name, source) = value.split_once('=').unwrap_or((value, value));
+        let name = name.trim();
+        let source = match source.trim().to_ascii_lowercase().as_str() {
+            "method" => FieldSource::Method,
+            "path" => FieldSource::Path,
+            "client_ip" => FieldSource::ClientIp,
+            s => FieldSource::Header(
+                HeaderName::from_bytes(s.strip_prefix("header:")?.trim().as_bytes()).ok()?,
+            ),
+        };
+        if name.is_empty() {
+            return None;
+        }
+        Some(LogField {
+            name: name.to_owned(),
+            source,
+        })
+    }
+
+    fn value(&self, req: &ServiceRequest) -> Option<String> {
+        match &self.source {
+            FieldSource::Header(h) => req.headers().get(h)?.to_str().ok().map(|v| v.to_owned()),
+            FieldSource::Method => Some(req.method().to_string()),
+            FieldSource::Path => Some(req.path().to_owned()),
+            FieldSource::ClientIp => {
+                let info = req.connection_info();
+                let addr = info.realip_remote_addr()?;
+                Some(
+                    addr.parse::<SocketAddr>()
+                        .map(|a| a.ip().to_string())
+                        .unwrap_or_else(|_| addr.to_owned()),
+                )
+            }
+        }
+    }
+}
+
+/// Parse `fields`, warning on invalid entries.
+pub(crate) fn parse_log_fields(fields: &[String]) -> Vec<LogField> {
+    let mut parsed = vec![];
+    for f in fields {
+        match LogField::parse(f) {
+            Some(f) => parsed.push(f),
+            _ => warn!("Invalid log field {}", f),
+        }
+    }
+    parsed
+}
+
+/// Print `fields` after the trace fields, `padding` is already printed.
+pub(crate) fn add_log_fields<W>(mut log: FallLog<W>, fields: &[String]) -> FallLog<W>
+where
+    W: io::Write + Send + 'static,
+{
+    for f in parse_log_fields(fields) {
+        if f.name != PADDING {
+            log = log.add_field(f.name);
+        }
+    }
+    log
+}
+
 pub struct FallTransform<H>
 where
     H: RequestHandler,
 {
     handler: Rc<H>,
+    headers: Rc<Vec<TraceHeader>>,
+    fields: Rc<Vec<LogField>>,
 }
 
 impl<H> FallTransform<H>
@@ -32,6 +178,31 @@ where
     pub fn new(handler: H) -> Self {
         FallTransform {
             handler: Rc::new(handler),
+            headers: Rc::new(vec![]),
+            fields: Rc::new(vec![]),
+        }
+    }
+
+    /// Span fields set from each request, see `LogField`.
+    pub fn log_fields(self, fields: &[String]) -> Self {
+        FallTransform {
+            fields: Rc::new(parse_log_fields(fields)),
+            ..self
+        }
+    }
+
+    /// Response headers carrying the trace, `X-Request-Id`, `X-B3-TraceId` or `traceresponse`.
+    pub fn response_headers(self, names: &[String]) -> Self {
+        let mut headers = vec![];
+        for name in names {
+            match TraceHeader::parse(name) {
+                Some(h) => headers.push(h),
+                _ => warn!("Unknown trace response header {}", name),
+            }
+        }
+        FallTransform {
+            headers: Rc::new(headers),
+            ..self
         }
     }
 }
@@ -48,6 +219,8 @@ where
 {
     service: Rc<RefCell<S>>,
     handler: Rc<H>,
+    headers: Rc<Vec<TraceHeader>>,
+    fields: Rc<Vec<LogField>>,
 }
 
 impl<S, H, B> Transform<S> for FallTransform<H>
@@ -68,6 +241,8 @@ where
         future::ok(FallMiddleware {
             service: Rc::new(RefCell::new(service)),
             handler: self.handler.clone(),
+            headers: self.headers.clone(),
+            fields: self.fields.clone(),
         })
     }
 }
@@ -89,19 +264,104 @@ where
     fn call(&mut self, req: ServiceRequest) -> Self::Future {
         let mut sv = self.service.clone();
         let hd = self.handler.clone();
+        let headers = self.headers.clone();
+        let fields = self.fields.clone();
+        let span = hd.new_span(&req);
+        let request_id = read_request_id(&req);
+        if let Some(id) = &request_id {
+            span.record(REQUEST_ID, display(id));
+        }
+        let trace = span.in_scope(|| {
+            for f in fields.iter() {
+                if let Some(v) = f.value(&req) {
+                    set_span_field(&f.name, &v);
+                }
+            }
+            current_trace()
+        });
+        let ctx = ErrorContext {
+            config: req
+                .app_data::<Data<ErrorConfig>>()
+                .map(|c| c.clone().into_inner())
+                .unwrap_or_default(),
+            trace_id: trace.as_ref().map(|t| t.trace_id.clone()),
+            path: Some(req.path().to_owned()),
+            accept_language: req
+                .headers()
+                .get("Accept-Language")
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_owned()),
+        };
+        // Entered on each poll only, requests interleaved on a worker keep their own span.
         async move {
-            let span = hd.new_span(&req);
-            let _enter = span.enter();
-            match hd.pre_request(&req).await {
-                Ok(()) => sv.call(req).await,
-                Err(e) => Ok(req.error_response(e)),
+            let add_headers = |map: &mut HeaderMap| {
+                if let Some(trace) = &trace {
+                    for h in headers.iter() {
+                        if let Ok(v) = HeaderValue::from_str(&h.value(trace, request_id.as_deref()))
+                        {
+                            map.insert(h.name(), v);
+                        }
+                    }
+                }
+            };
+            let res = match hd.pre_request(&req).await {
+                Ok(()) => {
+                    AssertUnwindSafe(async move { sv.call(req).await })
+                        .catch_unwind()
+                        .await
+                }
+                Err(e) => Ok(Ok(req.error_response(e))),
+            };
+            match res {
+                Ok(res) => res
+                    .map(|r| render_error(hd.post_response(r), &ctx))
+                    .map(|mut r| {
+                        add_headers(r.headers_mut());
+                        r
+                    }),
+                Err(payload) => {
+                    let (location, backtrace) = take_panic().unwrap_or_default();
+                    // Already logged with the backtrace by the panic hook.
+                    if !panic_hook_installed() {
+                        error!(
+                            "Handler panicked at {}: {}\n{}",
+                            location,
+                            panic_message(&*payload),
+                            backtrace
+                        );
+                    }
+                    // The request is dropped with the handler, so reply through the error.
+                    let mut res = FallError::internal("Handler panicked").render(&ctx);
+                    add_headers(res.headers_mut());
+                    Err(res.into())
+                }
             }
-            .map(|r| hd.post_response(r))
         }
+        .instrument(span)
         .boxed_local()
     }
 }
 
+/// Render the `FallError` of `res` with the request values of `ctx`, keeping other headers.
+fn render_error<B>(res: ServiceResponse<B>, ctx: &ErrorContext) -> ServiceResponse<B> {
+    let body = match res
+        .response()
+        .error()
+        .and_then(|e| e.as_error::<FallError>())
+    {
+        Some(e) => e.render(ctx),
+        _ => return res,
+    };
+    let headers = res.headers().clone();
+    let mut res = res.into_response(body.into_body());
+    for (name, value) in headers.iter() {
+        if name != CONTENT_TYPE && name != CONTENT_LENGTH {
+            res.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+    res
+}
+
 fn read_header_as_u64(name: &str, req: &ServiceRequest) -> Option<u64> {
     req.headers()
         .get(name)
@@ -109,6 +369,16 @@ fn read_header_as_u64(name: &str, req: &ServiceRequest) -> Option<u64> {
         .and_then(|r| u64::from_str_radix(r, 16).ok())
 }
 
+/// Inbound `X-Request-Id`, ignored if longer than 128 characters.
+fn read_request_id(req: &ServiceRequest) -> Option<String> {
+    req.headers()
+        .get("X-Request-Id")
+        .and_then(|r| r.to_str().ok())
+        .map(|r| r.trim())
+        .filter(|r| !r.is_empty() && r.len() <= 128)
+        .map(|r| r.to_owned())
+}
+
 pub fn from_req(req: &ServiceRequest) -> OpenTrace {
     let trace_id = match read_header_as_u64("X-B3-TraceId", req) {
         Some(v) => v,
@@ -118,9 +388,110 @@ pub fn from_req(req: &ServiceRequest) -> OpenTrace {
         Some(v) => v,
         _ => trace_id,
     };
-    OpenTrace::new(
+    let mut trace = OpenTrace::new(
         trace_id,
         span_id,
         read_header_as_u64("X-B3-ParentSpanId", req),
-    )
+    );
+    trace.baggage = read_baggage(req.headers());
+    trace
+}
+
+/// Maximum baggage entries read from a request, as in W3C baggage.
+const MAX_BAGGAGE: usize = 180;
+
+/// Baggage from W3C `baggage: k1=v1,k2=v2;prop` and prefixed `baggage-k: v` headers.
+///
+/// Prefixed headers override entries of the `baggage` header.
+pub(crate) fn read_baggage(headers: &HeaderMap) -> BTreeMap<String, String> {
+    let mut baggage = BTreeMap::new();
+    for v in headers.get_all("baggage").filter_map(|v| v.to_str().ok()) {
+        for entry in v.split(',') {
+            let entry = entry.split(';').next().unwrap_or("");
+            if let Some((k, v)) = entry.split_once('=') {
+                let k = k.trim();
+                if !k.is_empty() {
+                    baggage.insert(k.to_owned(), percent_decode(v.trim()));
+                }
+            }
+        }
+    }
+    for (name, v) in headers.iter() {
+        if let Some(k) = name.as_str().strip_prefix("baggage-") {
+            if let Ok(v) = v.to_str() {
+                baggage.insert(k.to_owned(), v.trim().to_owned());
+            }
+        }
+    }
+    baggage.into_iter().take(MAX_BAGGAGE).collect()
+}
+
+/// W3C `baggage` header value, values percent encoded.
+pub(crate) fn write_baggage(baggage: &BTreeMap<String, String>) -> String {
+    let mut value = String::new();
+    for (k, v) in baggage.iter() {
+        if !value.is_empty() {
+            value.push(',');
+        }
+        value.push_str(k);
+        value.push('=');
+        for b in v.bytes() {
+            if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+                value.push(b as char);
+            } else {
+                value.push_str(&format!("%{:02X}", b));
+            }
+        }
+    }
+    value
+}
+
+fn percent_decode(v: &str) -> String {
+    let bytes = v.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::web::*;
+    use actix_web::http::HeaderValue;
+
+    #[test]
+    fn test_baggage() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("baggage"),
+            HeaderValue::from_static("tenant_id=t1, user_id=u%201;p=1,bad"),
+        );
+        headers.insert(
+            HeaderName::from_static("baggage-bucket"),
+            HeaderValue::from_static("b"),
+        );
+        let baggage = read_baggage(&headers);
+        assert_eq!(3, baggage.len());
+        assert_eq!("u 1", baggage["user_id"]);
+        assert_eq!("b", baggage["bucket"]);
+        assert_eq!(
+            "bucket=b,tenant_id=t1,user_id=u%201",
+            write_baggage(&baggage)
+        );
+    }
 }